@@ -0,0 +1,75 @@
+//! Solver scaling on generated circuits with thousands of nodes.
+//! Run with `cargo bench --bench scaling`.
+
+use std::time::{Duration, Instant};
+
+use circuit_sim::bipoles::{Capacitor, Circuit, Resistor, SimulationOptions, SinusoidalVoltageSource, VoltageSource};
+
+/// A source driving `sections` series resistors, each node shunted to ground.
+fn resistor_ladder(sections: usize) -> Circuit {
+    let mut circ = Circuit::new(0);
+    circ.add_bipole(Box::new(VoltageSource::new(1.0)), 1, 0, String::from("V1"));
+    for k in 1..=sections {
+        circ.add_bipole(Box::new(Resistor::new(1.0)), k, k + 1, format!("RS{k}"));
+        circ.add_bipole(Box::new(Resistor::new(100.0)), k + 1, 0, format!("RP{k}"));
+    }
+    circ
+}
+
+/// A `side` x `side` grid of resistors with a capacitor from every node to ground,
+/// driven at one corner.
+fn rc_mesh(side: usize) -> Circuit {
+    let node = |row: usize, column: usize| 1 + row * side + column;
+    let mut circ = Circuit::new(0);
+    circ.add_bipole(Box::new(SinusoidalVoltageSource::new(1.0, 1e3)), node(0, 0), 0, String::from("V1"));
+    for row in 0..side {
+        for column in 0..side {
+            if column + 1 < side {
+                circ.add_bipole(Box::new(Resistor::new(10.0)), node(row, column), node(row, column + 1),
+                    format!("RH{row}_{column}"));
+            }
+            if row + 1 < side {
+                circ.add_bipole(Box::new(Resistor::new(10.0)), node(row, column), node(row + 1, column),
+                    format!("RV{row}_{column}"));
+            }
+            circ.add_bipole(Box::new(Capacitor::new(1e-6, 0.0)), node(row, column), 0,
+                format!("C{row}_{column}"));
+        }
+    }
+    circ
+}
+
+fn report(name: &str, nodes: usize, steps: usize, elapsed: Duration) {
+    println!("{name:<24} {nodes:>6} nodes {steps:>5} solves {:>10.3} ms total {:>8.3} ms/solve",
+        elapsed.as_secs_f64() * 1e3, elapsed.as_secs_f64() * 1e3/steps as f64);
+}
+
+fn main() {
+    for sections in [1000, 2000, 4000, 8000] {
+        let mut circ = resistor_ladder(sections);
+        let start = Instant::now();
+        circ.operating_point().unwrap();
+        report("resistor ladder, DC", sections + 2, 1, start.elapsed());
+    }
+
+    for sections in [1000, 4000] {
+        let mut circ = resistor_ladder(sections);
+        let start = Instant::now();
+        circ.simulate(1e-3, 1e-5).unwrap();
+        report("resistor ladder, tran", sections + 2, 100, start.elapsed());
+    }
+
+    for side in [16, 32, 64] {
+        let mut circ = rc_mesh(side);
+        let start = Instant::now();
+        circ.simulate(1e-3, 1e-5).unwrap();
+        report("RC mesh, tran", side * side + 1, 100, start.elapsed());
+
+        // the same mesh factorised at every step instead of once
+        let mut circ = rc_mesh(side);
+        let options = SimulationOptions { always_refactor: true, ..Default::default() };
+        let start = Instant::now();
+        circ.simulate_with_options(1e-3, 1e-5, &options).unwrap();
+        report("RC mesh, tran, refactor", side * side + 1, 100, start.elapsed());
+    }
+}
@@ -0,0 +1,368 @@
+//! Operational amplifiers, as three-terminal devices with non-inverting input, inverting
+//! input and an output referenced to ground.
+
+use std::f64::consts;
+use mathru::algebra::abstr::Complex;
+
+use crate::bipoles::{check_finite, check_positive, BipoleBehaviour, Capacitor, IntegrationMethod, Model, NewtonOptions,
+    TimestepControl};
+use crate::devices::{Device, Solution, Stamper, Step, GROUND};
+use crate::topology::BranchKind;
+
+/// Ideal op-amp: a nullator across the inputs, which forces them to the same voltage and draws
+/// no current, and a norator from the output to ground, which delivers whatever current the
+/// circuit needs. Only works inside negative feedback.
+#[derive(Clone, Default)]
+pub struct IdealOpAmp;
+
+impl IdealOpAmp {
+    pub fn new() -> IdealOpAmp {
+        IdealOpAmp
+    }
+}
+
+impl Device for IdealOpAmp {
+    fn terminals(&self) -> &'static [&'static str] {
+        &["p", "n", "out"]
+    }
+
+    fn branches(&self, _step: Step) -> usize {1}
+
+    fn ac_branches(&self) -> usize {1}
+
+    fn stamp(&self, _step: Step, stamper: &mut Stamper<f64>) {
+        let (output_i, output) = (stamper.branch(0), stamper.node(2));
+        stamper.add(output, output_i, 1.0);
+        stamper.add(output_i, stamper.node(0), 1.0);
+        stamper.add(output_i, stamper.node(1), -1.0);
+    }
+
+    fn stamp_ac(&self, _omega: f64, stamper: &mut Stamper<Complex<f64>>) {
+        let (output_i, output) = (stamper.branch(0), stamper.node(2));
+        stamper.add(output, output_i, Complex::new(1.0, 0.0));
+        stamper.add(output_i, stamper.node(0), Complex::new(1.0, 0.0));
+        stamper.add(output_i, stamper.node(1), Complex::new(-1.0, 0.0));
+    }
+
+    fn currents(&self, _step: Step, solution: &Solution<f64>) -> Vec<f64> {
+        vec![0.0, 0.0, solution.branch_current(0)]
+    }
+
+    fn ac_currents(&self, _omega: f64, solution: &Solution<Complex<f64>>) -> Vec<Complex<f64>> {
+        let zero = Complex::new(0.0, 0.0);
+        vec![zero, zero, solution.branch_current(0)]
+    }
+
+    fn topology(&self, _step: Step) -> Vec<(usize, usize, BranchKind)> {
+        vec![(2, GROUND, BranchKind::VoltageDefined)]
+    }
+}
+
+/// Width of the band below each rail where the output bends over, as a fraction of the swing.
+const RAIL_BAND: f64 = 0.01;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OpAmpParameters {
+    /// Open-loop DC gain.
+    pub gain: f64,
+    /// Gain-bandwidth product, placing the single pole at `gain_bandwidth_hz/gain`; infinite
+    /// for an amplifier without a pole.
+    pub gain_bandwidth_hz: f64,
+    /// Resistance between the inputs; infinite when they draw no current.
+    pub input_resistance: f64,
+    pub output_resistance: f64,
+    /// Lowest and highest output voltages, approached smoothly within `RAIL_BAND` of the swing;
+    /// `None` for an unbounded output.
+    pub rails: Option<(f64, f64)>
+}
+
+impl Default for OpAmpParameters {
+    fn default() -> Self {
+        OpAmpParameters { gain: 1e5, gain_bandwidth_hz: 1e6, input_resistance: f64::INFINITY,
+            output_resistance: 0.0, rails: None }
+    }
+}
+
+/// Op-amp macromodel: the amplified input difference drives an internal voltage through a
+/// single pole, and the output follows that voltage, clipped to the rails, behind the output
+/// resistance. The output current and the internal voltage are the two branch unknowns.
+#[derive(Clone)]
+pub struct OpAmp {
+    parameters: OpAmpParameters,
+    /// Integrates the internal voltage with a "capacitance" equal to the pole's time constant.
+    pole: Capacitor,
+    /// Internal voltage of the last Newton iterate.
+    voltage_internal: f64
+}
+
+impl OpAmp {
+    pub fn new(parameters: OpAmpParameters) -> OpAmp {
+        OpAmp { parameters, pole: Capacitor::new(parameters.gain/(2.0 * consts::PI * parameters.gain_bandwidth_hz), 0.0),
+            voltage_internal: 0.0 }
+    }
+
+    fn has_pole(&self) -> bool {
+        self.parameters.gain_bandwidth_hz.is_finite()
+    }
+
+    /// Output voltage before the output resistance, and its derivative by the internal voltage.
+    /// Between the rails the output is the internal voltage; in the band next to a rail it
+    /// turns exponentially towards it, with a continuous slope.
+    fn clip(&self, voltage: f64) -> (f64, f64) {
+        let (low, high) = match self.parameters.rails {
+            Some(rails) => rails,
+            None => return (voltage, 1.0)
+        };
+        let band = RAIL_BAND * (high - low);
+        if voltage > high - band {
+            let slope = (-(voltage - high + band)/band).exp();
+            (high - band * slope, slope)
+        } else if voltage < low + band {
+            let slope = ((voltage - low - band)/band).exp();
+            (low + band * slope, slope)
+        } else {
+            (voltage, 1.0)
+        }
+    }
+}
+
+impl Device for OpAmp {
+    fn terminals(&self) -> &'static [&'static str] {
+        &["p", "n", "out"]
+    }
+
+    fn branches(&self, _step: Step) -> usize {2}
+
+    fn ac_branches(&self) -> usize {2}
+
+    fn stamp(&self, step: Step, stamper: &mut Stamper<f64>) {
+        let p = &self.parameters;
+        let (positive, negative, output) = (stamper.node(0), stamper.node(1), stamper.node(2));
+        let (output_i, internal) = (stamper.branch(0), stamper.branch(1));
+        if p.input_resistance.is_finite() {
+            stamper.add_conductance(positive, negative, 1.0/p.input_resistance);
+        }
+
+        // pole time constant * d(internal)/dt + internal = gain * (v(p) - v(n))
+        let (conduttance, current) = match (step, self.has_pole()) {
+            (Step::Transient { timestep_sec, time, method }, true) => {
+                match self.pole.linear_companion(timestep_sec, time, method) {
+                    Model::ConduttanceCurrentSource { conduttance, current } => (conduttance, current),
+                    _ => unreachable!("a capacitor companion is a conductance and a current source")
+                }
+            }
+            _ => (0.0, 0.0)
+        };
+        stamper.add(internal, internal, 1.0 + conduttance);
+        stamper.add(internal, positive, -p.gain);
+        stamper.add(internal, negative, p.gain);
+        stamper.add_source(internal, -current);
+
+        // v(out) - output resistance * output current = clip(internal)
+        let (clipped, slope) = self.clip(self.voltage_internal);
+        stamper.add(output, output_i, 1.0);
+        stamper.add(output_i, output, 1.0);
+        stamper.add(output_i, output_i, -p.output_resistance);
+        stamper.add(output_i, internal, -slope);
+        stamper.add_source(output_i, clipped - slope * self.voltage_internal);
+    }
+
+    fn stamp_ac(&self, omega: f64, stamper: &mut Stamper<Complex<f64>>) {
+        let p = &self.parameters;
+        let (positive, negative, output) = (stamper.node(0), stamper.node(1), stamper.node(2));
+        let (output_i, internal) = (stamper.branch(0), stamper.branch(1));
+        if p.input_resistance.is_finite() {
+            stamper.add_conductance(positive, negative, Complex::new(1.0/p.input_resistance, 0.0));
+        }
+
+        let time_constant = if self.has_pole() { p.gain/(2.0 * consts::PI * p.gain_bandwidth_hz) } else { 0.0 };
+        stamper.add(internal, internal, Complex::new(1.0, omega * time_constant));
+        stamper.add(internal, positive, Complex::new(-p.gain, 0.0));
+        stamper.add(internal, negative, Complex::new(p.gain, 0.0));
+
+        let slope = self.clip(self.voltage_internal).1;
+        stamper.add(output, output_i, Complex::new(1.0, 0.0));
+        stamper.add(output_i, output, Complex::new(1.0, 0.0));
+        stamper.add(output_i, output_i, Complex::new(-p.output_resistance, 0.0));
+        stamper.add(output_i, internal, Complex::new(-slope, 0.0));
+    }
+
+    fn currents(&self, _step: Step, solution: &Solution<f64>) -> Vec<f64> {
+        let input_i = (solution.voltage(0) - solution.voltage(1))/self.parameters.input_resistance;
+        vec![input_i, -input_i, solution.branch_current(0)]
+    }
+
+    fn ac_currents(&self, _omega: f64, solution: &Solution<Complex<f64>>) -> Vec<Complex<f64>> {
+        let input_i = (solution.voltage(0) - solution.voltage(1)) * Complex::new(1.0/self.parameters.input_resistance, 0.0);
+        vec![input_i, Complex::new(0.0, 0.0) - input_i, solution.branch_current(0)]
+    }
+
+    fn topology(&self, _step: Step) -> Vec<(usize, usize, BranchKind)> {
+        let mut pairs = vec![(2, GROUND, if self.parameters.output_resistance > 0.0 {
+            BranchKind::Conductive
+        } else {
+            BranchKind::VoltageDefined
+        })];
+        if self.parameters.input_resistance.is_finite() {
+            pairs.push((0, 1, BranchKind::Conductive));
+        }
+        pairs
+    }
+
+    fn is_dynamic(&self) -> bool {
+        self.has_pole()
+    }
+
+    fn is_nonlinear(&self) -> bool {
+        self.parameters.rails.is_some()
+    }
+
+    fn update_operating_point(&mut self, solution: &Solution<f64>) {
+        self.voltage_internal = solution.branch_current(1);
+    }
+
+    fn reset_operating_point(&mut self, _newton: &NewtonOptions) {
+        self.voltage_internal = 0.0;
+    }
+
+    fn warm_start(&mut self, solution: &Solution<f64>, _newton: &NewtonOptions) {
+        self.voltage_internal = solution.branch_current(1);
+    }
+
+    fn update_state(&mut self, solution: &Solution<f64>, timestep_sec: f64, method: IntegrationMethod) {
+        if self.has_pole() {
+            self.pole.update_state(solution.branch_current(1), 0.0, timestep_sec, method);
+        }
+    }
+
+    fn truncation_error_ratio(&self, solution: &Solution<f64>, timestep_sec: f64,
+        method: IntegrationMethod, control: &TimestepControl) -> f64 {
+
+        if !self.has_pole() {
+            return 0.0;
+        }
+        self.pole.truncation_error_ratio(solution.branch_current(1), 0.0, timestep_sec, method, control)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let p = &self.parameters;
+        check_positive("gain", p.gain)?;
+        if p.gain_bandwidth_hz.is_nan() || p.gain_bandwidth_hz <= 0.0 {
+            return Err(format!("gain-bandwidth product must be positive, got {}", p.gain_bandwidth_hz));
+        }
+        if p.input_resistance.is_nan() || p.input_resistance <= 0.0 {
+            return Err(format!("input resistance must be positive, got {}", p.input_resistance));
+        }
+        check_finite("output resistance", p.output_resistance)?;
+        if p.output_resistance < 0.0 {
+            return Err(format!("output resistance must not be negative, got {}", p.output_resistance));
+        }
+        match p.rails {
+            Some((low, high)) if !low.is_finite() || !high.is_finite() || low >= high =>
+                Err(format!("rails must be finite with the lowest first, got {low} and {high}")),
+            _ => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bipoles::{Circuit, Resistor, Sweep, VoltageSource};
+
+    fn inverting(amplifier: Box<dyn Device>) -> Circuit {
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(VoltageSource::new_ac(1.0, 1.0, 0.0)), "in", "0", String::from("VIN"));
+        circ.add_bipole_between(Box::new(Resistor::new(1e3)), "in", "n", String::from("R1"));
+        circ.add_bipole_between(Box::new(Resistor::new(10e3)), "n", "out", String::from("RF"));
+        circ.add_device_between(amplifier, &["0", "n", "out"], String::from("X1"));
+        circ
+    }
+
+    #[test]
+    fn test_inverting_amplifier() {
+        let op = inverting(Box::new(IdealOpAmp::new())).operating_point().unwrap();
+        assert!((op.voltage("out").unwrap() + 10.0).abs() < 1e-12);
+        assert!(op.voltage("n").unwrap().abs() < 1e-12);
+        // the output sinks the 1 mA through the feedback resistor
+        assert!((op.currents["X1.out"] - 1e-3).abs() < 1e-15);
+
+        let mut circ = inverting(Box::new(OpAmp::new(OpAmpParameters::default())));
+        let op = circ.operating_point().unwrap();
+        assert!((op.voltage("out").unwrap() + 10.0/(1.0 + 11.0/1e5)).abs() < 1e-9);
+
+        // the closed-loop bandwidth is the gain-bandwidth product over the noise gain of 11
+        let ac = circ.ac_sweep(Sweep::Linear, 1, 1e6/11.0, 1e6/11.0).unwrap();
+        let gain = ac.voltage("out").unwrap()[0];
+        assert!(((gain.re * gain.re + gain.im * gain.im).sqrt() * 2f64.sqrt()/10.0 - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_non_inverting_amplifier() {
+        let amplifier = |parameters: Option<OpAmpParameters>| {
+            let mut circ = Circuit::new(0);
+            circ.add_bipole_between(Box::new(VoltageSource::new_ac(0.5, 1.0, 0.0)), "in", "0", String::from("VIN"));
+            circ.add_bipole_between(Box::new(Resistor::new(1e3)), "n", "0", String::from("RG"));
+            circ.add_bipole_between(Box::new(Resistor::new(9e3)), "out", "n", String::from("RF"));
+            let amplifier: Box<dyn Device> = match parameters {
+                Some(parameters) => Box::new(OpAmp::new(parameters)),
+                None => Box::new(IdealOpAmp::new())
+            };
+            circ.add_device_between(amplifier, &["in", "n", "out"], String::from("X1"));
+            circ
+        };
+        let follower = |parameters: Option<OpAmpParameters>| amplifier(parameters).operating_point().unwrap().voltage("out").unwrap();
+
+        assert!((follower(None) - 5.0).abs() < 1e-12);
+        let parameters = OpAmpParameters { output_resistance: 100.0, ..OpAmpParameters::default() };
+        // the output resistance loses 1% into the 10k divider
+        assert!((follower(Some(parameters)) - 5.0/(1.0 + 10.0 * 1.01/1e5)).abs() < 1e-9);
+        // five volts out is past the rails, where the output clips
+        let parameters = OpAmpParameters { rails: Some((-3.0, 3.0)), ..OpAmpParameters::default() };
+        assert!((follower(Some(parameters)) - 3.0).abs() < 1e-6);
+        // well inside the rails they change neither the output nor the gain around it
+        let railed = OpAmpParameters { rails: Some((-3.0, 12.0)), ..OpAmpParameters::default() };
+        assert!((follower(Some(railed)) - 5.0/(1.0 + 10.0/1e5)).abs() < 1e-9);
+        let gain = |parameters: OpAmpParameters| {
+            let ac = amplifier(Some(parameters)).ac_sweep(Sweep::Linear, 1, 1.0, 1.0).unwrap();
+            ac.voltage("out").unwrap()[0]
+        };
+        let (railed, unrailed) = (gain(railed), gain(OpAmpParameters::default()));
+        assert!((railed.re - unrailed.re).abs() < 1e-9 && (railed.im - unrailed.im).abs() < 1e-9);
+        assert!((unrailed.re - 10.0).abs() < 1e-2);
+        // open loop, the output stage passes the internal voltage with unit slope up to the
+        // band next to each rail, and bends over continuously inside it
+        let output_stage = OpAmp::new(OpAmpParameters { rails: Some((-3.0, 12.0)), ..OpAmpParameters::default() });
+        assert_eq!(output_stage.clip(5.0), (5.0, 1.0));
+        assert_eq!(output_stage.clip(11.85), (11.85, 1.0));
+        let (near_rail, slope) = output_stage.clip(11.85 + 1e-9);
+        assert!((near_rail - 11.85).abs() < 2e-9 && (slope - 1.0).abs() < 1e-7);
+        assert!(output_stage.clip(12.0).0 < 12.0 && output_stage.clip(-3.0).0 > -3.0);
+        assert_eq!((output_stage.clip(100.0).0, output_stage.clip(-100.0).0), (12.0, -3.0));
+    }
+
+    #[test]
+    fn test_integrator() {
+        let integrator = |amplifier: Box<dyn Device>| {
+            let mut circ = Circuit::new(0);
+            circ.add_bipole_between(Box::new(VoltageSource::new(1.0)), "in", "0", String::from("VIN"));
+            circ.add_bipole_between(Box::new(Resistor::new(1e3)), "in", "n", String::from("R1"));
+            circ.add_bipole_between(Box::new(Capacitor::new(1e-6, 0.0)), "n", "out", String::from("C1"));
+            circ.add_device_between(amplifier, &["0", "n", "out"], String::from("X1"));
+            circ.simulate(10e-3, 1e-5).unwrap()
+        };
+
+        // a ramp of -1 V/ms from the state one step before the first sample
+        let out = integrator(Box::new(IdealOpAmp::new()));
+        for (time, voltage) in out.time.iter().zip(out.voltage("out").unwrap().iter()) {
+            assert!((voltage + (time + 1e-5) * 1e3).abs() < 1e-9);
+        }
+
+        // the macromodel follows the ramp until it reaches the lower rail
+        let parameters = OpAmpParameters { rails: Some((-5.0, 5.0)), ..OpAmpParameters::default() };
+        let out = integrator(Box::new(OpAmp::new(parameters)));
+        let voltage = out.voltage("out").unwrap();
+        assert!((voltage[199] + 2.0).abs() < 1e-2);
+        assert!((voltage[out.time.iter().count() - 1] + 5.0).abs() < 1e-2);
+    }
+}
@@ -0,0 +1,287 @@
+//! Devices with any number of terminals. A device stamps its linearised equations straight
+//! into the MNA system: entries between any of its unknowns, extra branch unknowns of its own
+//! (with the equation in their row) and right-hand side contributions. Bipoles are devices
+//! with two terminals, see `bipoles::BipoleBehaviour`.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use mathru::algebra::abstr::Complex;
+
+use crate::bipoles::{IntegrationMethod, NewtonOptions, TimestepControl};
+use crate::sparse::{Element, SparseMatrix};
+use crate::topology::BranchKind;
+
+/// The analysis a device is stamped for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    /// A step of `timestep_sec` ending at `time`.
+    Transient { timestep_sec: f64, time: f64, method: IntegrationMethod },
+    /// DC operating point, with capacitors open and inductors shorted.
+    OperatingPoint
+}
+
+impl Step {
+    pub fn time(&self) -> Option<f64> {
+        match *self {
+            Step::Transient { time, .. } => Some(time),
+            Step::OperatingPoint => None
+        }
+    }
+}
+
+/// Terminal index standing for the circuit's ground in `Device::topology`, for outputs
+/// referenced to ground rather than to a terminal of the device.
+pub const GROUND: usize = usize::MAX;
+
+pub trait Device {
+    /// Names of the terminals, in the order their nodes are given to `Circuit::add_device`.
+    fn terminals(&self) -> &'static [&'static str];
+
+    /// Branch unknowns added to the system; the device owns their rows.
+    fn branches(&self, _step: Step) -> usize {0}
+
+    fn ac_branches(&self) -> usize {0}
+
+    fn stamp(&self, step: Step, stamper: &mut Stamper<f64>);
+
+    /// Small-signal stamp around the last operating point; independent sources are zeroed.
+    fn stamp_ac(&self, omega: f64, stamper: &mut Stamper<Complex<f64>>);
+
+    /// Currents flowing into each terminal from the circuit, for the stamp of `step`.
+    fn currents(&self, step: Step, solution: &Solution<f64>) -> Vec<f64>;
+
+    fn ac_currents(&self, omega: f64, solution: &Solution<Complex<f64>>) -> Vec<Complex<f64>>;
+
+    /// Pairs of terminals, by index, and how the device joins them for the topology checks.
+    fn topology(&self, step: Step) -> Vec<(usize, usize, BranchKind)>;
+
+    fn is_dynamic(&self) -> bool {false}
+
+    fn is_nonlinear(&self) -> bool {false}
+
+    /// Moves the linearisation of a nonlinear device to a new Newton iterate.
+    fn update_operating_point(&mut self, _solution: &Solution<f64>) {}
+
+    /// Starts a Newton solve from scratch, which converges by the criteria of `newton`.
+    fn reset_operating_point(&mut self, _newton: &NewtonOptions) {}
+
+    /// Starts the next Newton solve from an already converged solution.
+    fn warm_start(&mut self, _solution: &Solution<f64>, _newton: &NewtonOptions) {}
+
+    /// Accepts a transient step.
+    fn update_state(&mut self, _solution: &Solution<f64>, _timestep_sec: f64, _method: IntegrationMethod) {}
+
+    /// Truncation error of a step ending at `solution`, relative to the error allowed by `control`.
+    fn truncation_error_ratio(&self, _solution: &Solution<f64>, _timestep_sec: f64,
+        _method: IntegrationMethod, _control: &TimestepControl) -> f64 {0.0}
+
+    /// First corner of a source waveform strictly after `time`; transient steps end on it.
+    fn next_breakpoint(&self, _time: f64) -> Option<f64> {None}
+
+    /// Devices whose first branch current this one senses.
+    fn controlling_devices(&self) -> Vec<&str> {Vec::new()}
+
+    /// Checks the parameters before a simulation, describing the first problem found.
+    fn validate(&self) -> Result<(), String> {Ok(())}
+}
+
+/// Where the terminals and branches of one device sit among the unknowns of the system.
+#[derive(Clone, Copy)]
+pub(crate) struct Placement<'a> {
+    pub terminals: &'a [usize],
+    pub branches: &'a Range<usize>,
+    /// Branch unknowns of every device in the circuit.
+    pub device_branches: &'a HashMap<String, Range<usize>>
+}
+
+impl Placement<'_> {
+    fn node(&self, terminal: usize) -> usize {
+        self.terminals[terminal]
+    }
+
+    fn branch(&self, index: usize) -> usize {
+        assert!(index < self.branches.len(), "branch {index} was not requested by the device");
+        self.branches.start + index
+    }
+
+    fn branch_of(&self, device: &str) -> Option<usize> {
+        self.device_branches.get(device).filter(|branches| !branches.is_empty()).map(|branches| branches.start)
+    }
+}
+
+/// Write access to the MNA system for one device. Rows and columns are unknowns of the
+/// whole system, as returned by `node`, `branch` and `branch_of`.
+pub struct Stamper<'a, T> {
+    placement: Placement<'a>,
+    matrix: &'a mut SparseMatrix<T>,
+    sources: &'a mut [T]
+}
+
+impl<'a, T: Element> Stamper<'a, T> {
+    pub(crate) fn new(placement: Placement<'a>, matrix: &'a mut SparseMatrix<T>, sources: &'a mut [T]) -> Stamper<'a, T> {
+        Stamper { placement, matrix, sources }
+    }
+
+    /// Unknown holding the voltage of a terminal.
+    pub fn node(&self, terminal: usize) -> usize {
+        self.placement.node(terminal)
+    }
+
+    /// Unknown holding one of the device's own branch currents.
+    pub fn branch(&self, index: usize) -> usize {
+        self.placement.branch(index)
+    }
+
+    /// Unknown holding the first branch current of another device, if it has one.
+    pub fn branch_of(&self, device: &str) -> Option<usize> {
+        self.placement.branch_of(device)
+    }
+
+    pub fn add(&mut self, row: usize, column: usize, value: T) {
+        self.matrix.add(row, column, value);
+    }
+
+    pub fn add_source(&mut self, row: usize, value: T) {
+        self.sources[row] = self.sources[row] + value;
+    }
+
+    /// A conductance between two node unknowns.
+    pub fn add_conductance(&mut self, anode: usize, catode: usize, conductance: T) {
+        self.matrix.add(anode, anode, conductance);
+        self.matrix.add(catode, catode, conductance);
+        self.matrix.add(anode, catode, T::zero() - conductance);
+        self.matrix.add(catode, anode, T::zero() - conductance);
+    }
+
+    /// A current flowing through the device from one node unknown to another.
+    pub fn add_current(&mut self, anode: usize, catode: usize, current: T) {
+        self.add_source(anode, T::zero() - current);
+        self.add_source(catode, current);
+    }
+
+    /// A current from `anode` to `catode` of `transconductance * (v(control anode) - v(control catode))`.
+    pub fn add_transconductance(&mut self, anode: usize, catode: usize,
+        control_anode: usize, control_catode: usize, transconductance: T) {
+        self.matrix.add(anode, control_anode, transconductance);
+        self.matrix.add(anode, control_catode, T::zero() - transconductance);
+        self.matrix.add(catode, control_anode, T::zero() - transconductance);
+        self.matrix.add(catode, control_catode, transconductance);
+    }
+}
+
+/// Read access to a solution of the system for one device.
+pub struct Solution<'a, T> {
+    placement: Placement<'a>,
+    values: &'a [T]
+}
+
+impl<'a, T: Element> Solution<'a, T> {
+    pub(crate) fn new(placement: Placement<'a>, values: &'a [T]) -> Solution<'a, T> {
+        Solution { placement, values }
+    }
+
+    /// Voltage of a terminal.
+    pub fn voltage(&self, terminal: usize) -> T {
+        self.values[self.placement.node(terminal)]
+    }
+
+    pub fn branch_current(&self, index: usize) -> T {
+        self.values[self.placement.branch(index)]
+    }
+
+    /// Any unknown of the system, as returned by the `Stamper`.
+    pub fn value(&self, unknown: usize) -> T {
+        self.values[unknown]
+    }
+
+    pub fn branch_of(&self, device: &str) -> Option<usize> {
+        self.placement.branch_of(device)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bipoles::{Circuit, Resistor, SimulationError, VoltageSource};
+
+    /// Three resistors joined at a star point, whose voltage is kept as the device's own unknown.
+    struct Star {
+        resistances: [f64; 3]
+    }
+
+    impl Device for Star {
+        fn terminals(&self) -> &'static [&'static str] {
+            &["a", "b", "c"]
+        }
+
+        fn branches(&self, _step: Step) -> usize {1}
+
+        fn ac_branches(&self) -> usize {1}
+
+        fn stamp(&self, _step: Step, stamper: &mut Stamper<f64>) {
+            let star = stamper.branch(0);
+            for (terminal, resistance) in self.resistances.iter().enumerate() {
+                stamper.add_conductance(stamper.node(terminal), star, 1.0/resistance);
+            }
+        }
+
+        fn stamp_ac(&self, _omega: f64, stamper: &mut Stamper<Complex<f64>>) {
+            let star = stamper.branch(0);
+            for (terminal, resistance) in self.resistances.iter().enumerate() {
+                stamper.add_conductance(stamper.node(terminal), star, Complex::new(1.0/resistance, 0.0));
+            }
+        }
+
+        fn currents(&self, _step: Step, solution: &Solution<f64>) -> Vec<f64> {
+            let star = solution.branch_current(0);
+            (0..3).map(|terminal| (solution.voltage(terminal) - star)/self.resistances[terminal]).collect()
+        }
+
+        fn ac_currents(&self, _omega: f64, solution: &Solution<Complex<f64>>) -> Vec<Complex<f64>> {
+            let star = solution.branch_current(0);
+            (0..3).map(|terminal| (solution.voltage(terminal) - star) * Complex::new(1.0/self.resistances[terminal], 0.0))
+                .collect()
+        }
+
+        fn topology(&self, _step: Step) -> Vec<(usize, usize, BranchKind)> {
+            vec![(0, 1, BranchKind::Conductive), (0, 2, BranchKind::Conductive)]
+        }
+    }
+
+    #[test]
+    fn test_three_terminal_device() {
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(VoltageSource::new_ac(10.0, 1.0, 0.0)), "in", "0", String::from("V1"));
+        circ.add_device_between(Box::new(Star { resistances: [1000.0, 2000.0, 2000.0] }), &["in", "out", "0"],
+            String::from("X1"));
+        circ.add_bipole_between(Box::new(Resistor::new(2000.0)), "out", "0", String::from("R1"));
+
+        // the same network built from resistors
+        let mut discrete = Circuit::new(0);
+        discrete.add_bipole_between(Box::new(VoltageSource::new(10.0)), "in", "0", String::from("V1"));
+        discrete.add_bipole_between(Box::new(Resistor::new(1000.0)), "in", "star", String::from("RA"));
+        discrete.add_bipole_between(Box::new(Resistor::new(2000.0)), "out", "star", String::from("RB"));
+        discrete.add_bipole_between(Box::new(Resistor::new(2000.0)), "0", "star", String::from("RC"));
+        discrete.add_bipole_between(Box::new(Resistor::new(2000.0)), "out", "0", String::from("R1"));
+
+        let op = circ.operating_point().unwrap();
+        let expected = discrete.operating_point().unwrap();
+        assert!((op.voltage("out").unwrap() - expected.voltage("out").unwrap()).abs() < 1e-9);
+        assert!((op.currents["X1.a"] - expected.currents["RA"]).abs() < 1e-12);
+        assert!((op.currents["X1.b"] - expected.currents["RB"]).abs() < 1e-12);
+        assert!((op.currents["X1.a"] + op.currents["X1.b"] + op.currents["X1.c"]).abs() < 1e-12);
+
+        let ac = circ.ac_sweep(crate::bipoles::Sweep::Linear, 2, 1.0, 2.0).unwrap();
+        let gain = expected.voltage("out").unwrap()/10.0;
+        assert!((ac.voltage("out").unwrap()[1].re - gain).abs() < 1e-9);
+        assert!((ac.currents["X1.a"][0].re - expected.currents["RA"]/10.0).abs() < 1e-12);
+
+        let out = circ.simulate(1e-3, 1e-4).unwrap();
+        assert!((out.voltage("out").unwrap()[4] - expected.voltage("out").unwrap()).abs() < 1e-9);
+
+        // every terminal must be connected
+        circ.add_device(Box::new(Star { resistances: [1.0, 1.0, 1.0] }), &[1, 0], String::from("X2"));
+        assert!(matches!(circ.operating_point(),
+            Err(SimulationError::InvalidParameter { element, message }) if element == "X2" && message == "needs 3 terminals, got 2"));
+    }
+}
@@ -1,3 +1,13 @@
-
+pub mod amplifiers;
 pub mod bipoles;
-pub mod plotter;
\ No newline at end of file
+pub mod devices;
+pub mod magnetics;
+pub mod netlist;
+pub mod plotter;
+pub mod schematic;
+pub mod sparse;
+pub mod switches;
+pub mod topology;
+pub mod transistors;
+pub mod transmission_lines;
+pub mod waveforms;
\ No newline at end of file
@@ -0,0 +1,339 @@
+//! Magnetically coupled windings: a pair of coupled inductors (SPICE `K` linking two `L`
+//! elements) and an ideal transformer. Both are four-terminal devices with the windings
+//! between terminals `p1`-`n1` and `p2`-`n2`, dotted at `p1` and `p2`.
+
+use mathru::algebra::abstr::Complex;
+
+use crate::bipoles::{check_finite, check_positive, IntegrationMethod, TimestepControl};
+use crate::devices::{Device, Solution, Stamper, Step};
+use crate::topology::BranchKind;
+
+/// Two inductors sharing the mutual inductance `coupling * sqrt(L1 * L2)`. The winding currents
+/// are branch unknowns, so the pair also works with perfect coupling.
+#[derive(Clone)]
+pub struct CoupledInductors {
+    inductances: [f64; 2],
+    coupling: f64,
+    current_i: [f64; 2],
+    current_voltage: [f64; 2],
+    previous_i: [f64; 2],
+    older_i: [f64; 2],
+    previous_timestep_sec: Option<f64>,
+    older_timestep_sec: Option<f64>
+}
+
+impl CoupledInductors {
+    pub fn new(inductances: [f64; 2], coupling: f64, initial_i: [f64; 2]) -> CoupledInductors {
+        CoupledInductors { inductances, coupling, current_i: initial_i, current_voltage: [0.0; 2],
+            previous_i: initial_i, older_i: initial_i, previous_timestep_sec: None, older_timestep_sec: None }
+    }
+
+    pub fn mutual_inductance(&self) -> f64 {
+        self.coupling * (self.inductances[0] * self.inductances[1]).sqrt()
+    }
+
+    /// Flux linked by each winding.
+    fn fluxes(&self, currents: [f64; 2]) -> [f64; 2] {
+        let mutual = self.mutual_inductance();
+        [self.inductances[0] * currents[0] + mutual * currents[1], mutual * currents[0] + self.inductances[1] * currents[1]]
+    }
+
+    /// Companion of v = dflux/dt as `v - scale * flux = history` for each winding; the
+    /// trapezoidal rule starts with a backward Euler step, as the `Inductor` does.
+    fn companion(&self, timestep_sec: f64, method: IntegrationMethod) -> (f64, [f64; 2]) {
+        let fluxes = self.fluxes(self.current_i);
+        if method == IntegrationMethod::Trapezoidal && self.previous_timestep_sec.is_some() {
+            let scale = 2.0/timestep_sec;
+            return (scale, [0, 1].map(|winding| -scale * fluxes[winding] - self.current_voltage[winding]));
+        }
+
+        let (a0, a1, a2) = method.bdf_coefficients(timestep_sec, self.previous_timestep_sec);
+        let previous_fluxes = self.fluxes(self.previous_i);
+        (a0/timestep_sec, [0, 1].map(|winding| (a1 * fluxes[winding] + a2 * previous_fluxes[winding])/timestep_sec))
+    }
+}
+
+impl Device for CoupledInductors {
+    fn terminals(&self) -> &'static [&'static str] {
+        &["p1", "n1", "p2", "n2"]
+    }
+
+    fn branches(&self, _step: Step) -> usize {2}
+
+    fn ac_branches(&self) -> usize {2}
+
+    fn stamp(&self, step: Step, stamper: &mut Stamper<f64>) {
+        // at DC both windings are shorts
+        let (scale, history) = match step {
+            Step::Transient { timestep_sec, method, .. } => self.companion(timestep_sec, method),
+            Step::OperatingPoint => (0.0, [0.0; 2])
+        };
+        let mutual = self.mutual_inductance();
+        let branches = [stamper.branch(0), stamper.branch(1)];
+        for winding in 0..2 {
+            let (positive, negative, branch) = (stamper.node(2 * winding), stamper.node(2 * winding + 1), branches[winding]);
+            stamper.add(positive, branch, 1.0);
+            stamper.add(negative, branch, -1.0);
+
+            stamper.add(branch, positive, 1.0);
+            stamper.add(branch, negative, -1.0);
+            stamper.add(branch, branch, -scale * self.inductances[winding]);
+            stamper.add(branch, branches[1 - winding], -scale * mutual);
+            stamper.add_source(branch, history[winding]);
+        }
+    }
+
+    fn stamp_ac(&self, omega: f64, stamper: &mut Stamper<Complex<f64>>) {
+        let one = Complex::new(1.0, 0.0);
+        let mutual = self.mutual_inductance();
+        let branches = [stamper.branch(0), stamper.branch(1)];
+        for winding in 0..2 {
+            let (positive, negative, branch) = (stamper.node(2 * winding), stamper.node(2 * winding + 1), branches[winding]);
+            stamper.add(positive, branch, one);
+            stamper.add(negative, branch, -one);
+
+            stamper.add(branch, positive, one);
+            stamper.add(branch, negative, -one);
+            stamper.add(branch, branch, Complex::new(0.0, -omega * self.inductances[winding]));
+            stamper.add(branch, branches[1 - winding], Complex::new(0.0, -omega * mutual));
+        }
+    }
+
+    fn currents(&self, _step: Step, solution: &Solution<f64>) -> Vec<f64> {
+        let (primary, secondary) = (solution.branch_current(0), solution.branch_current(1));
+        vec![primary, -primary, secondary, -secondary]
+    }
+
+    fn ac_currents(&self, _omega: f64, solution: &Solution<Complex<f64>>) -> Vec<Complex<f64>> {
+        let zero = Complex::new(0.0, 0.0);
+        let (primary, secondary) = (solution.branch_current(0), solution.branch_current(1));
+        vec![primary, zero - primary, secondary, zero - secondary]
+    }
+
+    fn topology(&self, step: Step) -> Vec<(usize, usize, BranchKind)> {
+        let kind = match step {
+            Step::Transient { .. } => BranchKind::Conductive,
+            Step::OperatingPoint => BranchKind::VoltageDefined
+        };
+        vec![(0, 1, kind), (2, 3, kind)]
+    }
+
+    fn is_dynamic(&self) -> bool {
+        true
+    }
+
+    fn update_state(&mut self, solution: &Solution<f64>, timestep_sec: f64, _method: IntegrationMethod) {
+        self.older_i = self.previous_i;
+        self.previous_i = self.current_i;
+        self.current_i = [solution.branch_current(0), solution.branch_current(1)];
+        self.current_voltage = [solution.voltage(0) - solution.voltage(1), solution.voltage(2) - solution.voltage(3)];
+        self.older_timestep_sec = self.previous_timestep_sec;
+        self.previous_timestep_sec = Some(timestep_sec);
+    }
+
+    fn truncation_error_ratio(&self, solution: &Solution<f64>, timestep_sec: f64,
+        method: IntegrationMethod, control: &TimestepControl) -> f64 {
+
+        let history = [[solution.branch_current(0), solution.branch_current(1)], self.current_i, self.previous_i, self.older_i]
+            .map(|currents| self.fluxes(currents));
+        let timesteps_sec: Vec<f64> = [Some(timestep_sec), self.previous_timestep_sec, self.older_timestep_sec]
+            .into_iter().map_while(|timestep_sec| timestep_sec).collect();
+
+        (0..2).map(|winding| {
+            let fluxes = history.map(|fluxes| fluxes[winding]);
+            control.error_ratio(method, &fluxes[..timesteps_sec.len() + 1], &timesteps_sec)
+        }).fold(0.0, f64::max)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        check_positive("primary inductance", self.inductances[0])?;
+        check_positive("secondary inductance", self.inductances[1])?;
+        if self.coupling.is_nan() || self.coupling.abs() > 1.0 {
+            return Err(format!("coupling coefficient must be between -1 and 1, got {}", self.coupling));
+        }
+        check_finite("primary initial current", self.current_i[0])?;
+        check_finite("secondary initial current", self.current_i[1])
+    }
+}
+
+/// Ideal transformer with `turns_ratio` primary turns per secondary turn:
+/// v(p1, n1) = turns_ratio * v(p2, n2), and the secondary delivers turns_ratio times the
+/// primary current. It passes DC, and the primary current is its branch unknown.
+#[derive(Clone)]
+pub struct IdealTransformer {
+    turns_ratio: f64
+}
+
+impl IdealTransformer {
+    pub fn new(turns_ratio: f64) -> IdealTransformer {
+        IdealTransformer { turns_ratio }
+    }
+}
+
+impl Device for IdealTransformer {
+    fn terminals(&self) -> &'static [&'static str] {
+        &["p1", "n1", "p2", "n2"]
+    }
+
+    fn branches(&self, _step: Step) -> usize {1}
+
+    fn ac_branches(&self) -> usize {1}
+
+    fn stamp(&self, _step: Step, stamper: &mut Stamper<f64>) {
+        let branch = stamper.branch(0);
+        let terminals = [1.0, -1.0, -self.turns_ratio, self.turns_ratio];
+        for (terminal, coefficient) in terminals.into_iter().enumerate() {
+            stamper.add(stamper.node(terminal), branch, coefficient);
+            stamper.add(branch, stamper.node(terminal), coefficient);
+        }
+    }
+
+    fn stamp_ac(&self, _omega: f64, stamper: &mut Stamper<Complex<f64>>) {
+        let branch = stamper.branch(0);
+        let terminals = [1.0, -1.0, -self.turns_ratio, self.turns_ratio];
+        for (terminal, coefficient) in terminals.into_iter().enumerate() {
+            stamper.add(stamper.node(terminal), branch, Complex::new(coefficient, 0.0));
+            stamper.add(branch, stamper.node(terminal), Complex::new(coefficient, 0.0));
+        }
+    }
+
+    fn currents(&self, _step: Step, solution: &Solution<f64>) -> Vec<f64> {
+        let primary = solution.branch_current(0);
+        vec![primary, -primary, -self.turns_ratio * primary, self.turns_ratio * primary]
+    }
+
+    fn ac_currents(&self, _omega: f64, solution: &Solution<Complex<f64>>) -> Vec<Complex<f64>> {
+        let primary = solution.branch_current(0);
+        let ratio = Complex::new(self.turns_ratio, 0.0);
+        vec![primary, Complex::new(0.0, 0.0) - primary, Complex::new(0.0, 0.0) - ratio * primary, ratio * primary]
+    }
+
+    fn topology(&self, _step: Step) -> Vec<(usize, usize, BranchKind)> {
+        // the windings are galvanically isolated from each other
+        vec![(0, 1, BranchKind::Conductive), (2, 3, BranchKind::Conductive)]
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        check_finite("turns ratio", self.turns_ratio)?;
+        if self.turns_ratio == 0.0 {
+            return Err(String::from("turns ratio must not be zero"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bipoles::{Circuit, Inductor, Resistor, SimulationOptions, SinusoidalVoltageSource, Sweep, VoltageSource};
+
+    const METHODS: [IntegrationMethod; 3] =
+        [IntegrationMethod::BackwardEuler, IntegrationMethod::Trapezoidal, IntegrationMethod::Gear2];
+
+    /// A 1 V step through 1 ohm into the primary of `windings`, with `load` across the secondary.
+    fn driven(windings: Box<dyn Device>, load: f64) -> Circuit {
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(VoltageSource::new_ac(1.0, 1.0, 0.0)), "in", "0", String::from("V1"));
+        circ.add_bipole_between(Box::new(Resistor::new(1.0)), "in", "p", String::from("R1"));
+        circ.add_device_between(windings, &["p", "0", "s", "0"], String::from("K1"));
+        circ.add_bipole_between(Box::new(Resistor::new(load)), "s", "0", String::from("RL"));
+        circ
+    }
+
+    /// The same step into a single inductor.
+    fn reference(inductance: f64, method: IntegrationMethod) -> Vec<f64> {
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(VoltageSource::new(1.0)), "in", "0", String::from("V1"));
+        circ.add_bipole_between(Box::new(Resistor::new(1.0)), "in", "p", String::from("R1"));
+        circ.add_bipole_between(Box::new(Inductor::new(inductance, 0.0)), "p", "0", String::from("L1"));
+        let options = SimulationOptions { method, ..Default::default() };
+        circ.simulate_with_options(2e-3, 1e-5, &options).unwrap().currents["L1"].iter().copied().collect()
+    }
+
+    #[test]
+    fn test_open_secondary() {
+        // with no secondary current the primary is a plain 1 mH inductor and the secondary
+        // sees the primary voltage times M/L1
+        for method in METHODS {
+            let mut circ = driven(Box::new(CoupledInductors::new([1e-3, 4e-3], 0.9, [0.0; 2])), 1e12);
+            let options = SimulationOptions { method, ..Default::default() };
+            let out = circ.simulate_with_options(2e-3, 1e-5, &options).unwrap();
+            let (primary, secondary) = (out.voltage("p").unwrap(), out.voltage("s").unwrap());
+            for (sample, expected) in reference(1e-3, method).into_iter().enumerate() {
+                assert!((out.currents["K1.p1"][sample] - expected).abs() < 1e-9, "{method:?} {sample}");
+                assert!((secondary[sample] - 1.8 * primary[sample]).abs() < 1e-9, "{method:?} {sample}");
+            }
+        }
+
+        // the adaptive step follows the flux of the windings
+        let mut circ = driven(Box::new(CoupledInductors::new([1e-3, 4e-3], 0.9, [0.0; 2])), 1e12);
+        let options = SimulationOptions { method: IntegrationMethod::Trapezoidal,
+            timestep_control: Some(TimestepControl::new(1e-9, 1e-4)), ..Default::default() };
+        let out = circ.simulate_with_options(2e-3, 1e-6, &options).unwrap();
+        let time = out.time.iter().copied().collect::<Vec<f64>>();
+        let current = out.currents["K1.p1"].iter().copied().collect::<Vec<f64>>();
+        assert!(time.len() < 100);
+        for (time, current) in time.iter().zip(&current) {
+            assert!((current - (1.0 - (-(time + 1e-6)/1e-3).exp())).abs() < 1e-3, "{time}");
+        }
+    }
+
+    #[test]
+    fn test_leakage_inductance() {
+        // a shorted secondary leaves the leakage inductance L1 (1 - k^2) in the primary
+        for method in METHODS {
+            let mut circ = driven(Box::new(CoupledInductors::new([1e-3, 4e-3], 0.9, [0.0; 2])), 1e-9);
+            let options = SimulationOptions { method, ..Default::default() };
+            let out = circ.simulate_with_options(2e-3, 1e-5, &options).unwrap();
+            for (sample, expected) in reference(1e-3 * (1.0 - 0.81), method).into_iter().enumerate() {
+                assert!((out.currents["K1.p1"][sample] - expected).abs() < 1e-6, "{method:?} {sample}");
+                // the secondary carries the primary ampere-turns back
+                assert!((out.currents["K1.p2"][sample] + 0.45 * out.currents["K1.p1"][sample]).abs() < 1e-6);
+            }
+        }
+
+        let mut circ = driven(Box::new(CoupledInductors::new([1e-3, 4e-3], 0.9, [0.0; 2])), 1e-9);
+        let omega = 2.0 * std::f64::consts::PI * 1e3;
+        let ac = circ.ac_sweep(Sweep::Linear, 1, 1e3, 1e3).unwrap();
+        let (v, i) = (ac.voltage("p").unwrap()[0], ac.currents["K1.p1"][0]);
+        let impedance = v/i;
+        assert!(impedance.re.abs() < 1e-6 && (impedance.im - omega * 1e-3 * 0.19).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_perfect_coupling() {
+        // with k = 1 the winding voltages keep the turns ratio sqrt(L2/L1) whatever the load
+        for method in METHODS {
+            let mut circ = Circuit::new(0);
+            circ.add_bipole_between(Box::new(SinusoidalVoltageSource::new(10.0, 1e3)), "in", "0", String::from("V1"));
+            circ.add_bipole_between(Box::new(Resistor::new(1.0)), "in", "p", String::from("R1"));
+            circ.add_device_between(Box::new(CoupledInductors::new([4e-3, 1e-3], 1.0, [0.0; 2])), &["p", "0", "s", "0"],
+                String::from("K1"));
+            circ.add_bipole_between(Box::new(Resistor::new(10.0)), "s", "0", String::from("RL"));
+            let options = SimulationOptions { method, ..Default::default() };
+            let out = circ.simulate_with_options(2e-3, 1e-5, &options).unwrap();
+            let (primary, secondary) = (out.voltage("p").unwrap(), out.voltage("s").unwrap());
+            for sample in 0..200 {
+                assert!((secondary[sample] - 0.5 * primary[sample]).abs() < 1e-9, "{method:?} {sample}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_ideal_transformer() {
+        // 2:1 step-down: the 100 ohm load shows up as 400 ohm on the primary
+        let mut circ = driven(Box::new(IdealTransformer::new(2.0)), 100.0);
+        let op = circ.operating_point().unwrap();
+        let primary = op.voltage("p").unwrap();
+        assert!((primary - 400.0/401.0).abs() < 1e-12);
+        assert!((op.voltage("s").unwrap() - primary/2.0).abs() < 1e-12);
+        assert!((op.currents["K1.p2"] + 2.0 * op.currents["K1.p1"]).abs() < 1e-12);
+        assert!((op.currents["K1.p2"] + op.voltage("s").unwrap()/100.0).abs() < 1e-12);
+
+        let ac = circ.ac_sweep(Sweep::Linear, 1, 1e3, 1e3).unwrap();
+        assert!((ac.voltage("s").unwrap()[0].re - ac.voltage("p").unwrap()[0].re/2.0).abs() < 1e-12);
+
+        assert!(matches!(driven(Box::new(IdealTransformer::new(0.0)), 1.0).operating_point(),
+            Err(crate::bipoles::SimulationError::InvalidParameter { message, .. }) if message == "turns ratio must not be zero"));
+    }
+}
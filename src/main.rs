@@ -1,9 +1,14 @@
 use macroquad::{prelude::*};
 use mathru::algebra::linear::Vector;
 use mathru::elementary::Power;
-use std::{collections::HashMap, vec, thread, time};
+use std::{collections::HashMap, path::Path, vec, thread, time};
 use std::f32::consts;
+use circuit_sim::amplifiers;
 use circuit_sim::bipoles;
+use circuit_sim::netlist;
+use circuit_sim::schematic;
+use circuit_sim::transistors;
+use circuit_sim::waveforms;
 use circuit_sim::plotter::PlotIterator;
 
 
@@ -15,10 +20,35 @@ use macroquad::ui::{
 trait BipoleFactory {
     fn set_parameter(&mut self, name: &str, value: f64);
 
-    fn make(&self) -> Box<dyn bipoles::BipoleBehaviour>;
+    /// Behaviour added by the default `add_to_circuit`; the components built as N-terminal
+    /// devices override `add_to_circuit` and never make one.
+    fn make(&self) -> Box<dyn bipoles::BipoleBehaviour> {
+        unreachable!("a component built as a device overrides add_to_circuit")
+    }
 
     fn get_parameters(&self) -> HashMap<String, f64>;
 
+    /// `extra_ids` are the nets of the pins returned by `extra_pins`.
+    fn spice_card(&self, name: &str, anode_id: usize, catode_id: usize, extra_ids: &[usize]) -> String;
+
+    /// Offsets from the symbol center of the pins besides anode and catode, with the anode along +x.
+    fn extra_pins(&self) -> Vec<Vec2> {
+        Vec::new()
+    }
+
+    fn add_to_circuit(&self, circuit: &mut bipoles::Circuit, name: String, anode_id: usize, catode_id: usize,
+        _extra_ids: &[usize]) {
+        circuit.add_bipole(self.make(), anode_id, catode_id, name);
+    }
+
+}
+
+fn spice_name(letter: char, name: &str) -> String {
+    if name.to_lowercase().starts_with(letter.to_ascii_lowercase()) {
+        String::from(name)
+    } else {
+        format!("{letter}{name}")
+    }
 }
 
 struct VoltageSourceFactory {
@@ -39,6 +69,10 @@ impl BipoleFactory for VoltageSourceFactory {
     fn make(&self) -> Box<dyn bipoles::BipoleBehaviour> {
         Box::new(bipoles::VoltageSource::new(self.value))
     }
+
+    fn spice_card(&self, name: &str, anode_id: usize, catode_id: usize, _extra_ids: &[usize]) -> String {
+        format!("{} {anode_id} {catode_id} DC {:e}", spice_name('V', name), self.value)
+    }
 }
 
 struct ResistorFactory {
@@ -59,6 +93,10 @@ impl BipoleFactory for ResistorFactory {
     fn make(&self) -> Box<dyn bipoles::BipoleBehaviour> {
         Box::new(bipoles::Resistor::new(self.resistance))
     }
+
+    fn spice_card(&self, name: &str, anode_id: usize, catode_id: usize, _extra_ids: &[usize]) -> String {
+        format!("{} {anode_id} {catode_id} {:e}", spice_name('R', name), self.resistance)
+    }
 }
 
 struct CapacitorFactory {
@@ -79,6 +117,10 @@ impl BipoleFactory for CapacitorFactory {
     fn make(&self) -> Box<dyn bipoles::BipoleBehaviour> {
         Box::new(bipoles::Capacitor::new(self.capacitance, 0.0))
     }
+
+    fn spice_card(&self, name: &str, anode_id: usize, catode_id: usize, _extra_ids: &[usize]) -> String {
+        format!("{} {anode_id} {catode_id} {:e}", spice_name('C', name), self.capacitance)
+    }
 }
 
 
@@ -100,6 +142,10 @@ impl BipoleFactory for InductorFactory {
     fn make(&self) -> Box<dyn bipoles::BipoleBehaviour> {
         Box::new(bipoles::Inductor::new(self.induttance, 0.0))
     }
+
+    fn spice_card(&self, name: &str, anode_id: usize, catode_id: usize, _extra_ids: &[usize]) -> String {
+        format!("{} {anode_id} {catode_id} {:e}", spice_name('L', name), self.induttance)
+    }
 }
 
 struct DiodeFactory {
@@ -123,6 +169,12 @@ impl BipoleFactory for DiodeFactory {
     fn make(&self) -> Box<dyn bipoles::BipoleBehaviour> {
         Box::new(bipoles::Diode::new(self.current_s, self.voltage_vt, 1.08, 0.9))
     }
+
+    fn spice_card(&self, name: &str, anode_id: usize, catode_id: usize, _extra_ids: &[usize]) -> String {
+        let name = spice_name('D', name);
+        format!("{name} {anode_id} {catode_id} {name}_model\n.model {name}_model D(IS={:e} N={:e})",
+            self.current_s, self.voltage_vt/netlist::THERMAL_VOLTAGE)
+    }
 }
 
 struct SinusoidalVoltageSourceFactory {
@@ -146,6 +198,10 @@ impl BipoleFactory for SinusoidalVoltageSourceFactory {
     fn make(&self) -> Box<dyn bipoles::BipoleBehaviour> {
         Box::new(bipoles::SinusoidalVoltageSource::new(self.value, self.frequency_hz))
     }
+
+    fn spice_card(&self, name: &str, anode_id: usize, catode_id: usize, _extra_ids: &[usize]) -> String {
+        format!("{} {anode_id} {catode_id} SIN(0 {:e} {:e})", spice_name('V', name), self.value, self.frequency_hz)
+    }
 }
 
 struct CurrentSourceFactory {
@@ -166,8 +222,264 @@ impl BipoleFactory for CurrentSourceFactory {
     fn make(&self) -> Box<dyn bipoles::BipoleBehaviour> {
         Box::new(bipoles::CurrentSource::new(self.value))
     }
+
+    fn spice_card(&self, name: &str, anode_id: usize, catode_id: usize, _extra_ids: &[usize]) -> String {
+        format!("{} {anode_id} {catode_id} DC {:e}", spice_name('I', name), self.value)
+    }
 }
 
+struct SinusoidalCurrentSourceFactory {
+    value: f64,
+    frequency_hz: f64,
+    ac_magnitude: f64,
+    ac_phase_deg: f64,
+}
+
+impl BipoleFactory for SinusoidalCurrentSourceFactory {
+    fn set_parameter(&mut self, name: &str, value: f64) {
+        if name == "value" {
+            self.value = value;
+        } else if name == "freq" {
+            self.frequency_hz = value;
+        } else if name == "ac" {
+            self.ac_magnitude = value;
+        } else if name == "ac_phase" {
+            self.ac_phase_deg = value;
+        }
+    }
+
+    fn get_parameters(&self) -> HashMap<String, f64> {
+        HashMap::from([(String::from("value"), self.value), (String::from("freq"), self.frequency_hz),
+            (String::from("ac"), self.ac_magnitude), (String::from("ac_phase"), self.ac_phase_deg)])
+    }
+
+    fn make(&self) -> Box<dyn bipoles::BipoleBehaviour> {
+        let waveform = waveforms::Waveform::Sin { offset: 0.0, amplitude: self.value, frequency_hz: self.frequency_hz,
+            delay_sec: 0.0, damping: 0.0, phase_deg: 0.0 };
+        Box::new(waveforms::WaveformCurrentSource::new_ac(waveform, self.ac_magnitude, self.ac_phase_deg))
+    }
+
+    fn spice_card(&self, name: &str, anode_id: usize, catode_id: usize, _extra_ids: &[usize]) -> String {
+        format!("{} {anode_id} {catode_id} SIN(0 {:e} {:e}) AC {:e} {:e}", spice_name('I', name), self.value,
+            self.frequency_hz, self.ac_magnitude, self.ac_phase_deg)
+    }
+}
+
+/// Controlling pins of the voltage-controlled sources, below the positive and negative output pins.
+fn control_pins() -> Vec<Vec2> {
+    vec![vec2(20.0, 20.0), vec2(-20.0, 20.0)]
+}
+
+struct VcvsFactory {
+    gain: f64
+}
+
+impl BipoleFactory for VcvsFactory {
+    fn set_parameter(&mut self, name: &str, value: f64) {
+        if name == "gain" {
+            self.gain = value;
+        }
+    }
+
+    fn get_parameters(&self) -> HashMap<String, f64> {
+        HashMap::from([(String::from("gain"), self.gain)])
+    }
+
+    fn make(&self) -> Box<dyn bipoles::BipoleBehaviour> {
+        Box::new(bipoles::VoltageControlledVoltageSource::new(self.gain))
+    }
+
+    fn spice_card(&self, name: &str, anode_id: usize, catode_id: usize, extra_ids: &[usize]) -> String {
+        format!("{} {anode_id} {catode_id} {} {} {:e}", spice_name('E', name), extra_ids[0], extra_ids[1], self.gain)
+    }
+
+    fn extra_pins(&self) -> Vec<Vec2> {
+        control_pins()
+    }
+
+    fn add_to_circuit(&self, circuit: &mut bipoles::Circuit, name: String, anode_id: usize, catode_id: usize,
+        extra_ids: &[usize]) {
+        circuit.add_controlled_bipole(self.make(), anode_id, catode_id, extra_ids[0], extra_ids[1], name);
+    }
+}
+
+struct VccsFactory {
+    transconductance: f64
+}
+
+impl BipoleFactory for VccsFactory {
+    fn set_parameter(&mut self, name: &str, value: f64) {
+        if name == "gm" {
+            self.transconductance = value;
+        }
+    }
+
+    fn get_parameters(&self) -> HashMap<String, f64> {
+        HashMap::from([(String::from("gm"), self.transconductance)])
+    }
+
+    fn make(&self) -> Box<dyn bipoles::BipoleBehaviour> {
+        Box::new(bipoles::VoltageControlledCurrentSource::new(self.transconductance))
+    }
+
+    fn spice_card(&self, name: &str, anode_id: usize, catode_id: usize, extra_ids: &[usize]) -> String {
+        format!("{} {anode_id} {catode_id} {} {} {:e}", spice_name('G', name), extra_ids[0], extra_ids[1], self.transconductance)
+    }
+
+    fn extra_pins(&self) -> Vec<Vec2> {
+        control_pins()
+    }
+
+    fn add_to_circuit(&self, circuit: &mut bipoles::Circuit, name: String, anode_id: usize, catode_id: usize,
+        extra_ids: &[usize]) {
+        circuit.add_controlled_bipole(self.make(), anode_id, catode_id, extra_ids[0], extra_ids[1], name);
+    }
+}
+
+struct BjtFactory {
+    polarity: transistors::Polarity,
+    beta_f: f64,
+    current_s: f64
+}
+
+impl BipoleFactory for BjtFactory {
+    fn set_parameter(&mut self, name: &str, value: f64) {
+        if name == "bf" {
+            self.beta_f = value;
+        } else if name == "is" {
+            self.current_s = value;
+        }
+    }
+
+    fn get_parameters(&self) -> HashMap<String, f64> {
+        HashMap::from([(String::from("bf"), self.beta_f), (String::from("is"), self.current_s)])
+    }
+
+    /// The anode is the collector, the catode the emitter and the extra pin the base.
+    fn spice_card(&self, name: &str, anode_id: usize, catode_id: usize, extra_ids: &[usize]) -> String {
+        let name = spice_name('Q', name);
+        let kind = match self.polarity {
+            transistors::Polarity::Npn => "NPN",
+            transistors::Polarity::Pnp => "PNP"
+        };
+        format!("{name} {anode_id} {} {catode_id} {name}_model\n.model {name}_model {kind}(IS={:e} BF={:e})",
+            extra_ids[0], self.current_s, self.beta_f)
+    }
+
+    fn extra_pins(&self) -> Vec<Vec2> {
+        vec![vec2(0.0, 20.0)]
+    }
+
+    fn add_to_circuit(&self, circuit: &mut bipoles::Circuit, name: String, anode_id: usize, catode_id: usize,
+        extra_ids: &[usize]) {
+        let parameters = transistors::BjtParameters { current_s: self.current_s, beta_f: self.beta_f,
+            ..transistors::BjtParameters::default() };
+        circuit.add_device(Box::new(transistors::Bjt::new(self.polarity, parameters)),
+            &[anode_id, extra_ids[0], catode_id], name);
+    }
+}
+
+struct MosfetFactory {
+    channel: transistors::Channel,
+    threshold_voltage: f64,
+    transconductance: f64,
+    width: f64,
+    length: f64
+}
+
+impl BipoleFactory for MosfetFactory {
+    fn set_parameter(&mut self, name: &str, value: f64) {
+        match name {
+            "vto" => self.threshold_voltage = value,
+            "kp" => self.transconductance = value,
+            "w" => self.width = value,
+            "l" => self.length = value,
+            _ => {}
+        }
+    }
+
+    fn get_parameters(&self) -> HashMap<String, f64> {
+        HashMap::from([(String::from("vto"), self.threshold_voltage), (String::from("kp"), self.transconductance),
+            (String::from("w"), self.width), (String::from("l"), self.length)])
+    }
+
+    /// The anode is the drain, the catode the source and the extra pin the gate; the bulk is tied to the source.
+    fn spice_card(&self, name: &str, anode_id: usize, catode_id: usize, extra_ids: &[usize]) -> String {
+        let name = spice_name('M', name);
+        let kind = match self.channel {
+            transistors::Channel::N => "NMOS",
+            transistors::Channel::P => "PMOS"
+        };
+        format!("{name} {anode_id} {} {catode_id} {catode_id} {name}_model W={:e} L={:e}\n.model {name}_model {kind}(VTO={:e} KP={:e})",
+            extra_ids[0], self.width, self.length, self.threshold_voltage, self.transconductance)
+    }
+
+    fn extra_pins(&self) -> Vec<Vec2> {
+        vec![vec2(0.0, 20.0)]
+    }
+
+    fn add_to_circuit(&self, circuit: &mut bipoles::Circuit, name: String, anode_id: usize, catode_id: usize,
+        extra_ids: &[usize]) {
+        let parameters = transistors::MosfetParameters { threshold_voltage: self.threshold_voltage,
+            transconductance: self.transconductance, width: self.width, length: self.length,
+            ..transistors::MosfetParameters::default() };
+        circuit.add_device(Box::new(transistors::Mosfet::new(self.channel, parameters)),
+            &[anode_id, extra_ids[0], catode_id], name);
+    }
+}
+
+struct OpAmpFactory {
+    gain: f64,
+    gain_bandwidth_hz: f64,
+    rail: f64
+}
+
+impl BipoleFactory for OpAmpFactory {
+    fn set_parameter(&mut self, name: &str, value: f64) {
+        match name {
+            "gain" => self.gain = value,
+            "gbw" => self.gain_bandwidth_hz = value,
+            "rail" => self.rail = value,
+            _ => {}
+        }
+    }
+
+    fn get_parameters(&self) -> HashMap<String, f64> {
+        HashMap::from([(String::from("gain"), self.gain), (String::from("gbw"), self.gain_bandwidth_hz),
+            (String::from("rail"), self.rail)])
+    }
+
+    /// The anode is the output, the catode the inverting input and the extra pin the non-inverting
+    /// one. SPICE has no op-amp element: the gain and its pole become a VCCS into an RC on an
+    /// internal node, buffered by a VCVS, and the rails, which linear elements cannot clip to,
+    /// are only named in a comment.
+    fn spice_card(&self, name: &str, anode_id: usize, catode_id: usize, extra_ids: &[usize]) -> String {
+        let mut card = String::new();
+        if self.rail > 0.0 {
+            card += &format!("* {name}: the rails at -{0:e} and {0:e} V are not exported\n", self.rail);
+        }
+        if !self.gain_bandwidth_hz.is_finite() {
+            return card + &format!("{} {anode_id} 0 {} {catode_id} {:e}", spice_name('E', name), extra_ids[0], self.gain);
+        }
+        let time_constant = self.gain/(2.0 * std::f64::consts::PI * self.gain_bandwidth_hz);
+        card + &format!("{} 0 {name}_pole {} {catode_id} {:e}\n{} {name}_pole 0 1\n{} {name}_pole 0 {time_constant:e}\n\
+            {} {anode_id} 0 {name}_pole 0 1", spice_name('G', name), extra_ids[0], self.gain, spice_name('R', name),
+            spice_name('C', name), spice_name('E', name))
+    }
+
+    fn extra_pins(&self) -> Vec<Vec2> {
+        vec![vec2(-20.0, 20.0)]
+    }
+
+    /// Rails at plus and minus `rail`, or none when it is not positive.
+    fn add_to_circuit(&self, circuit: &mut bipoles::Circuit, name: String, anode_id: usize, catode_id: usize,
+        extra_ids: &[usize]) {
+        let parameters = amplifiers::OpAmpParameters { gain: self.gain, gain_bandwidth_hz: self.gain_bandwidth_hz,
+            rails: if self.rail > 0.0 { Some((-self.rail, self.rail)) } else { None },
+            ..amplifiers::OpAmpParameters::default() };
+        circuit.add_device(Box::new(amplifiers::OpAmp::new(parameters)), &[extra_ids[0], catode_id, anode_id], name);
+    }
+}
 
 
 struct Node {
@@ -225,6 +537,24 @@ impl BipoleRotation {
     fn get_matrix(angle: f32) -> Mat2 {
         mat2(vec2(angle.cos(), angle.sin()), vec2(- angle.sin(), angle.cos()))
     }
+
+    fn to_schematic(self) -> schematic::Rotation {
+        match self {
+            Self::AnodeUp => schematic::Rotation::AnodeUp,
+            Self::AnodeDown => schematic::Rotation::AnodeDown,
+            Self::AnodeLeft => schematic::Rotation::AnodeLeft,
+            Self::AnodeRight => schematic::Rotation::AnodeRight,
+        }
+    }
+
+    fn from_schematic(rotation: schematic::Rotation) -> BipoleRotation {
+        match rotation {
+            schematic::Rotation::AnodeUp => Self::AnodeUp,
+            schematic::Rotation::AnodeDown => Self::AnodeDown,
+            schematic::Rotation::AnodeLeft => Self::AnodeLeft,
+            schematic::Rotation::AnodeRight => Self::AnodeRight,
+        }
+    }
 }
 
 struct BipoleToPlace {
@@ -260,6 +590,10 @@ fn get_catode_position(size: Vec2, center_position: Vec2, rotation: BipoleRotati
     matrix * catode_pos_rel + center_position
 }
 
+fn get_pin_position(offset: Vec2, center_position: Vec2, rotation: BipoleRotation) -> Vec2 {
+    BipoleRotation::get_matrix(rotation.get_angle()) * offset + center_position
+}
+
 fn draw_plus(size: Vec2, center_position: Vec2, rotation: BipoleRotation){
     let plus_sign_pos = center_position 
                 + BipoleRotation::get_matrix(rotation.get_angle()) * vec2(20.0, 10.0);
@@ -289,6 +623,7 @@ struct PlacedBipole {
     name: String,
     anode_node_id: usize,
     catode_node_id: usize,
+    extra_node_ids: Vec<usize>,
     size: Vec2,
     center_position: Vec2,
     rotation: BipoleRotation,
@@ -296,40 +631,40 @@ struct PlacedBipole {
     kind: String
 }
 
+fn make_factory(kind: &str) -> Option<Box<dyn BipoleFactory>> {
+    match kind {
+        "resistor" => Some(Box::new(ResistorFactory {resistance: 10.0})),
+        "voltage source" => Some(Box::new(VoltageSourceFactory {value: 10.0})),
+        "capacitor" => Some(Box::new(CapacitorFactory {capacitance: 2e-5})),
+        "inductor" => Some(Box::new(InductorFactory {induttance: 2e-5})),
+        "current source" => Some(Box::new(CurrentSourceFactory {value: 1e-3})),
+        "diode" => Some(Box::new(DiodeFactory {current_s: 1.0e-15, voltage_vt: 26e-3})),
+        "sinusoidal" => Some(Box::new(SinusoidalVoltageSourceFactory {value: 10.0, frequency_hz: 1.0})),
+        "sinusoidal current" => Some(Box::new(SinusoidalCurrentSourceFactory {value: 1e-3, frequency_hz: 1.0,
+            ac_magnitude: 0.0, ac_phase_deg: 0.0})),
+        "vcvs" => Some(Box::new(VcvsFactory {gain: 10.0})),
+        "vccs" => Some(Box::new(VccsFactory {transconductance: 1e-3})),
+        "npn" => Some(Box::new(BjtFactory {polarity: transistors::Polarity::Npn, beta_f: 100.0, current_s: 1e-16})),
+        "pnp" => Some(Box::new(BjtFactory {polarity: transistors::Polarity::Pnp, beta_f: 100.0, current_s: 1e-16})),
+        "nmos" => Some(Box::new(MosfetFactory {channel: transistors::Channel::N, threshold_voltage: 0.7,
+            transconductance: 1e-4, width: 10e-6, length: 1e-6})),
+        "pmos" => Some(Box::new(MosfetFactory {channel: transistors::Channel::P, threshold_voltage: -0.7,
+            transconductance: 4e-5, width: 10e-6, length: 1e-6})),
+        "opamp" => Some(Box::new(OpAmpFactory {gain: 1e5, gain_bandwidth_hz: 1e6, rail: 15.0})),
+        _ => None
+    }
+}
+
 impl  PlacedBipole {
     fn new(name: String, bipole: &BipoleToPlace, anode_id: usize, catode_id: usize) -> PlacedBipole {
-        let factory: Box<dyn BipoleFactory>;
-        match bipole.kind.as_str() {
-            "resistor" => {
-                factory = Box::new(ResistorFactory {resistance: 10.0})
-            }
-            "voltage source" => {
-                factory = Box::new(VoltageSourceFactory {value: 10.0})
-            }
-            "capacitor" => {
-                factory = Box::new(CapacitorFactory {capacitance: 2e-5})
-            }
-            "inductor" => {
-                factory = Box::new(InductorFactory {induttance: 2e-5})
-            }
-            "current source" => {
-                factory = Box::new(CurrentSourceFactory {value: 1e-3})
-            }
-            "diode" => {
-                factory = Box::new(DiodeFactory {current_s: 1.0e-15, voltage_vt: 26e-3})
-            }
-            "sinusoidal" => {
-                factory = Box::new(SinusoidalVoltageSourceFactory {value: 10.0, frequency_hz: 1.0})
-            }
-            _ => {
-                factory = Box::new(ResistorFactory {resistance: 10.0})
-            }
-        }
+        let factory = make_factory(&bipole.kind)
+            .unwrap_or_else(|| Box::new(ResistorFactory {resistance: 10.0}));
 
         PlacedBipole { 
             name: name, 
             anode_node_id: anode_id, 
             catode_node_id: catode_id,
+            extra_node_ids: Vec::new(),
             size: bipole.size,
             center_position: convert_to_grid_pos(bipole.center_position, 20.0),
             rotation: bipole.rotation.clone(),
@@ -370,6 +705,9 @@ enum Command  {
     DeleteWire {id: usize},
     ChangeParameters{name: String, parameters: HashMap<String, f64>},
     RunSimulation{sim_time: f64, t_step: f64},
+    ExportNetlist{path: String},
+    SaveSchematic{path: String},
+    OpenSchematic{path: String},
     SetPlotInfo(Option<PlotInfo>),
     SetGround(usize)
 }
@@ -519,7 +857,7 @@ impl Mode for ClickMode {
         
     }
 
-    fn update(&mut self, event: ClickEvent, _info: UiInfo) -> Option<Command> {
+    fn update(&mut self, event: ClickEvent, info: UiInfo) -> Option<Command> {
         if is_mouse_button_pressed(MouseButton::Right) {
             self.clicked = false;
             return None;
@@ -553,6 +891,18 @@ impl Mode for ClickMode {
             ClickEvent::ToolbarClicked(ToolBarEvent::SetGroundClicked)  => {
                 Some(Command::ChangeMode(Box::new(SetGroundMode::new())))
             }
+            ClickEvent::ToolbarClicked(ToolBarEvent::ExportClicked)  => {
+                let path = default_netlist_path(info.schematic_path.as_deref());
+                Some(Command::ChangeMode(Box::new(FileMode::new(FileAction::ExportNetlist, path))))
+            }
+            ClickEvent::ToolbarClicked(ToolBarEvent::SaveClicked)  => {
+                let path = info.schematic_path.unwrap_or_else(|| String::from("schematic.json"));
+                Some(Command::ChangeMode(Box::new(FileMode::new(FileAction::SaveSchematic, path))))
+            }
+            ClickEvent::ToolbarClicked(ToolBarEvent::OpenClicked)  => {
+                let path = info.schematic_path.unwrap_or_else(|| String::from("schematic.json"));
+                Some(Command::ChangeMode(Box::new(FileMode::new(FileAction::OpenSchematic, path))))
+            }
             ClickEvent::BipoleClicked { name, parameters } => {
                 if self.clicked {
                     return  None;
@@ -627,6 +977,92 @@ impl Mode for RunMode {
 }
 
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FileAction {
+    ExportNetlist,
+    SaveSchematic,
+    OpenSchematic
+}
+
+impl FileAction {
+    fn label(self) -> &'static str {
+        match self {
+            FileAction::ExportNetlist => "Export netlist",
+            FileAction::SaveSchematic => "Save schematic",
+            FileAction::OpenSchematic => "Open schematic"
+        }
+    }
+
+    fn command(self, path: String) -> Command {
+        match self {
+            FileAction::ExportNetlist => Command::ExportNetlist { path },
+            FileAction::SaveSchematic => Command::SaveSchematic { path },
+            FileAction::OpenSchematic => Command::OpenSchematic { path }
+        }
+    }
+}
+
+/// Asks for the file a toolbar action reads or writes, starting from a suggested path.
+struct FileMode {
+    action: FileAction,
+    path_input: String,
+    clicked: bool,
+    done: bool
+}
+
+impl FileMode {
+    fn new(action: FileAction, path: String) -> FileMode {
+        FileMode { action, path_input: path, clicked: false, done: false }
+    }
+}
+
+impl Mode for FileMode {
+    fn draw(&mut self, _textures: &HashMap<String, Texture2D>) {
+        widgets::Window::new(hash!(), vec2(screen_width()/2.0-125.0, screen_height()/2.0-50.0), vec2(250., 100.))
+                .label(self.action.label())
+                .titlebar(true)
+                .ui(&mut root_ui(), |ui| {
+                    ui.input_text(hash!(), "file", &mut self.path_input);
+
+                    if ui.button(vec2(0.0, 50.0), "Ok") {
+                        self.clicked = true;
+                    }
+                });
+    }
+
+    fn update(&mut self, event: ClickEvent, info: UiInfo) -> Option<Command> {
+        if let ClickEvent::ToolbarClicked(_) = event {
+            let mut mode = ClickMode::new();
+            return mode.update(event, info);
+        }
+        if self.done || is_mouse_button_pressed(MouseButton::Right) {
+            return Some(Command::ChangeMode(Box::new(ClickMode::new())));
+        }
+        if self.clicked {
+            self.clicked = false;
+            self.done = true;
+            return Some(self.action.command(self.path_input.trim().to_string()));
+        }
+        None
+    }
+}
+
+/// Netlist file offered for export: the schematic's file with a `.cir` extension.
+fn default_netlist_path(schematic_path: Option<&str>) -> String {
+    match schematic_path {
+        Some(path) => Path::new(path).with_extension("cir").to_string_lossy().into_owned(),
+        None => String::from("netlist.cir")
+    }
+}
+
+/// Outcome of the last file operation, shown at the bottom of the editor.
+#[derive(Debug)]
+enum Status {
+    Done(String),
+    Failed(String)
+}
+
+
 struct PlaceMode {
     bipole: BipoleToPlace,
     selected: bool,
@@ -645,7 +1081,15 @@ impl  PlaceMode {
                 String::from("capacitor"), 
                 String::from("inductor"),
                 String::from("diode"),
-                String::from("sinusoidal")],
+                String::from("sinusoidal"),
+                String::from("sinusoidal current"),
+                String::from("vcvs"),
+                String::from("vccs"),
+                String::from("npn"),
+                String::from("pnp"),
+                String::from("nmos"),
+                String::from("pmos"),
+                String::from("opamp")],
             selected: false,
             window_rect: Rect::new(20.0, 70.0, 100.0, 200.0),
         }
@@ -835,7 +1279,11 @@ struct UiData {
     simulation_output: Option<bipoles::SimulationOutput>,
     plot_info: Option<PlotInfo>,
     ground_id: Option<usize>,
-
+    last_run: Option<(f64, f64)>,
+    highlighted_bipoles: Vec<String>,
+    highlighted_nets: Vec<usize>,
+    schematic_path: Option<String>,
+    status: Option<Status>
 }
 
 impl  UiData {
@@ -853,7 +1301,12 @@ impl  UiData {
             mode: Box::new(mode),
             simulation_output: None,
             plot_info: None, 
-            ground_id: None
+            ground_id: None,
+            last_run: None,
+            highlighted_bipoles: Vec::new(),
+            highlighted_nets: Vec::new(),
+            schematic_path: None,
+            status: None
         }
     }
 
@@ -888,16 +1341,23 @@ impl  UiData {
 
         self.current_bipole_id += 1;
         let name = String::from(&bipole.kind[0..1]) + &self.current_bipole_id.to_string();
-        self.placed_bipoles.insert(name.clone(), 
-            PlacedBipole::new(name, bipole, anode_id, catode_id));
+        let mut placed = PlacedBipole::new(name.clone(), bipole, anode_id, catode_id);
+        for offset in placed.factory.extra_pins() {
+            self.add_node(get_pin_position(offset, bipole.center_position, bipole.rotation));
+            placed.extra_node_ids.push(self.current_node_id);
+        }
 
-        let node1 = self.nodes.get_mut(&anode_id).unwrap();
-        node1.number_connected += 1;
-        let node2 = self.nodes.get_mut(&catode_id).unwrap();
-        node2.number_connected += 1;
+        for node_id in [anode_id, catode_id].iter().chain(&placed.extra_node_ids) {
+            self.nodes.get_mut(node_id).unwrap().number_connected += 1;
+        }
+        self.placed_bipoles.insert(name, placed);
     }
 
-    pub fn run(&mut self, sim_time: f64, t_step: f64) {
+    fn compute_nets(&mut self) -> usize {
+
+        for (id, node) in &mut self.nodes {
+            node.computed_id = *id;
+        }
 
         for (_, wire) in &self.wires {
             let id1 = self.nodes.get(&wire.node1_id).unwrap().computed_id;
@@ -917,8 +1377,11 @@ impl  UiData {
 
         let mut current_mapping = HashMap::new();
         let mut current_index = 0;
+        let mut node_ids: Vec<usize> = self.nodes.keys().copied().collect();
+        node_ids.sort();
 
-        for (_, node) in &mut self.nodes {
+        for node_id in node_ids {
+            let node = self.nodes.get_mut(&node_id).unwrap();
             if current_mapping.contains_key(&node.computed_id) {
                 node.computed_id = *current_mapping.get(&node.computed_id).unwrap();
             } else {
@@ -929,12 +1392,17 @@ impl  UiData {
 
         }
 
-        let ground_id;
         if let Some(id) = self.ground_id {
-            ground_id = self.nodes.get(&id).unwrap().computed_id;
+            self.nodes.get(&id).unwrap().computed_id
         } else {
-            ground_id = 0;
+            0
         }
+    }
+
+    pub fn run(&mut self, sim_time: f64, t_step: f64) {
+
+        let ground_id = self.compute_nets();
+        self.last_run = Some((sim_time, t_step));
 
         let mut circ = bipoles::Circuit::new(ground_id);
 
@@ -943,12 +1411,170 @@ impl  UiData {
             let anode_id = self.nodes.get(&bipole.anode_node_id).unwrap().computed_id;
             let catode_id = self.nodes.get(&bipole.catode_node_id).unwrap().computed_id;
 
-            circ.add_bipole(bipole.factory.make(), 
-                anode_id, catode_id, 
-                bipole.name.clone())
+            let extra_ids: Vec<usize> = bipole.extra_node_ids.iter()
+                .map(|node_id| self.nodes.get(node_id).unwrap().computed_id)
+                .collect();
+
+            bipole.factory.add_to_circuit(&mut circ, bipole.name.clone(),
+                anode_id, catode_id, &extra_ids)
+        }
+
+        self.highlighted_bipoles.clear();
+        self.highlighted_nets.clear();
+        self.simulation_output = match circ.simulate(sim_time, t_step) {
+            Ok(output) => Some(output),
+            Err(error) => {
+                if let bipoles::SimulationError::Topology(diagnostics) = &error {
+                    for diagnostic in diagnostics {
+                        self.highlighted_bipoles.extend_from_slice(diagnostic.bipoles());
+                        self.highlighted_nets.extend_from_slice(diagnostic.nodes());
+                    }
+                }
+                eprintln!("simulation failed: {error}");
+                None
+            }
+        };
+    }
+
+    fn netlist_text(&mut self) -> String {
+        let ground_id = self.compute_nets();
+        let spice_id = |id: usize| {
+            if id == ground_id {
+                0
+            } else if id < ground_id {
+                id + 1
+            } else {
+                id
+            }
+        };
+
+        let mut names: Vec<&String> = self.placed_bipoles.keys().collect();
+        names.sort();
+
+        let mut text = String::from("circuit_sim export\n");
+        for name in names {
+            let bipole = self.placed_bipoles.get(name).unwrap();
+            let anode_id = spice_id(self.nodes.get(&bipole.anode_node_id).unwrap().computed_id);
+            let catode_id = spice_id(self.nodes.get(&bipole.catode_node_id).unwrap().computed_id);
+            let extra_ids: Vec<usize> = bipole.extra_node_ids.iter()
+                .map(|node_id| spice_id(self.nodes.get(node_id).unwrap().computed_id))
+                .collect();
+            text += &bipole.factory.spice_card(name, anode_id, catode_id, &extra_ids);
+            text += "\n";
+        }
+
+        if let Some((sim_time, t_step)) = self.last_run {
+            text += &format!(".tran {t_step:e} {sim_time:e}\n");
+        }
+        text += ".end\n";
+        text
+    }
+
+    fn to_schematic(&self) -> schematic::Schematic {
+        let mut data = schematic::Schematic::new();
+
+        for (id, node) in &self.nodes {
+            data.nodes.push(schematic::NodeData { id: *id, position: (node.position.x, node.position.y) });
+        }
+        for (id, wire) in &self.wires {
+            data.wires.push(schematic::WireData { id: *id, node1_id: wire.node1_id, node2_id: wire.node2_id });
+        }
+        for bipole in self.placed_bipoles.values() {
+            data.bipoles.push(schematic::BipoleData {
+                name: bipole.name.clone(),
+                kind: bipole.kind.clone(),
+                center_position: (bipole.center_position.x, bipole.center_position.y),
+                size: (bipole.size.x, bipole.size.y),
+                rotation: bipole.rotation.to_schematic(),
+                anode_node_id: bipole.anode_node_id,
+                catode_node_id: bipole.catode_node_id,
+                extra_node_ids: bipole.extra_node_ids.clone(),
+                parameters: bipole.factory.get_parameters().into_iter().collect()
+            });
         }
+        data.nodes.sort_by_key(|node| node.id);
+        data.wires.sort_by_key(|wire| wire.id);
+        data.bipoles.sort_by(|a, b| a.name.cmp(&b.name));
+        data.ground_id = self.ground_id;
 
-        self.simulation_output =  Some(circ.simulate(sim_time, t_step));
+        data
+    }
+
+    fn from_schematic(data: schematic::Schematic) -> Result<UiData, schematic::SchematicError> {
+        let mut uidata = UiData::new();
+
+        for node in &data.nodes {
+            uidata.nodes.insert(node.id, Node {
+                position: vec2(node.position.0, node.position.1),
+                computed_id: node.id,
+                number_connected: 0 });
+            uidata.current_node_id = uidata.current_node_id.max(node.id);
+        }
+
+        for bipole in data.bipoles {
+            let mut factory = make_factory(&bipole.kind)
+                .ok_or_else(|| schematic::SchematicError::UnknownKind { name: bipole.name.clone(), kind: bipole.kind.clone() })?;
+            for (parameter, value) in &bipole.parameters {
+                factory.set_parameter(parameter, *value);
+            }
+            let expected = factory.extra_pins().len();
+            if bipole.extra_node_ids.len() != expected {
+                return Err(schematic::SchematicError::PinCount { name: bipole.name, kind: bipole.kind, expected,
+                    found: bipole.extra_node_ids.len() });
+            }
+
+            for node_id in [bipole.anode_node_id, bipole.catode_node_id].iter().chain(&bipole.extra_node_ids) {
+                uidata.nodes.get_mut(node_id).unwrap().number_connected += 1;
+            }
+
+            let number: usize = bipole.name.get(1..).and_then(|number| number.parse().ok()).unwrap_or(0);
+            uidata.current_bipole_id = uidata.current_bipole_id.max(number);
+
+            uidata.placed_bipoles.insert(bipole.name.clone(), PlacedBipole {
+                name: bipole.name,
+                anode_node_id: bipole.anode_node_id,
+                catode_node_id: bipole.catode_node_id,
+                extra_node_ids: bipole.extra_node_ids,
+                size: vec2(bipole.size.0, bipole.size.1),
+                center_position: vec2(bipole.center_position.0, bipole.center_position.1),
+                rotation: BipoleRotation::from_schematic(bipole.rotation),
+                factory,
+                kind: bipole.kind
+            });
+        }
+
+        for wire in &data.wires {
+            let node1 = uidata.nodes.get_mut(&wire.node1_id).unwrap();
+            node1.number_connected += 1;
+            let node1_pos = node1.position;
+            let node2 = uidata.nodes.get_mut(&wire.node2_id).unwrap();
+            node2.number_connected += 1;
+            let node2_pos = node2.position;
+
+            uidata.wires.insert(wire.id, Wire { node1_pos, node2_pos, node1_id: wire.node1_id, node2_id: wire.node2_id });
+            uidata.current_wire_id = uidata.current_wire_id.max(wire.id);
+        }
+        uidata.ground_id = data.ground_id;
+
+        Ok(uidata)
+    }
+
+    pub fn save(&mut self, path: &str) -> Result<(), schematic::SchematicError> {
+        self.to_schematic().save(path)?;
+        self.schematic_path = Some(String::from(path));
+        Ok(())
+    }
+
+    /// Replaces the editor contents with the schematic at `path`; on error they are left as they were.
+    pub fn open(&mut self, path: &str) -> Result<(), schematic::SchematicError> {
+        *self = schematic::Schematic::load(path).and_then(UiData::from_schematic)?;
+        self.schematic_path = Some(String::from(path));
+        Ok(())
+    }
+
+    pub fn export_netlist(&mut self, path: &str) -> std::io::Result<()> {
+        let text = self.netlist_text();
+        std::fs::write(path, text)
     }
 
     pub fn is_colliding_node(&self, pos: Vec2) -> Option<usize> {
@@ -1011,55 +1637,80 @@ impl  UiData {
     pub fn update(&mut self, event: ToolBarEvent){
         
         let click_event = self.generate_click_event(event);
-        let info = UiInfo {current_node_id: self.current_node_id};
+        let info = UiInfo {current_node_id: self.current_node_id, schematic_path: self.schematic_path.clone()};
 
         if let  Some(command) = self.mode.update(click_event, info) {
-            match command {
-                Command::PlaceBipole(bipole) => {
-                    self.add_bipole(&bipole);
-                }
-                Command::PlaceWire { node1_id, node2_id, node2_pos, is_new } => {
-                    if is_new {
-                        self.add_node(node2_pos);
-                    }
-                    self.add_wire(node1_id, node2_id);
+            self.execute(command);
+        }
+    }
 
+    fn execute(&mut self, command: Command) {
+        match command {
+            Command::PlaceBipole(bipole) => {
+                self.add_bipole(&bipole);
+            }
+            Command::PlaceWire { node1_id, node2_id, node2_pos, is_new } => {
+                if is_new {
+                    self.add_node(node2_pos);
                 }
-                Command::ChangeMode(mode) => {
-                    self.mode = mode;
-                }
-                Command::ChangeName { old_name, new_name } => {
-                    let bipole = self.placed_bipoles.remove(&old_name).unwrap();
-                    self.placed_bipoles.insert(new_name, bipole);
+                self.add_wire(node1_id, node2_id);
 
+            }
+            Command::ChangeMode(mode) => {
+                self.mode = mode;
+            }
+            Command::ChangeName { old_name, new_name } => {
+                let bipole = self.placed_bipoles.remove(&old_name).unwrap();
+                self.placed_bipoles.insert(new_name, bipole);
+
+            }
+            Command::ChangeParameters { name, parameters } => {
+                let bipole = self.placed_bipoles.get_mut(&name).unwrap();
+                for (par_name, value) in &parameters {
+                    bipole.factory.set_parameter(&par_name, *value);
                 }
-                Command::ChangeParameters { name, parameters } => {
-                    let bipole = self.placed_bipoles.get_mut(&name).unwrap();
-                    for (par_name, value) in &parameters {
-                        bipole.factory.set_parameter(&par_name, *value);
-                    }
-                }
-                Command::RunSimulation { sim_time, t_step } => {
-                    self.run(sim_time, t_step);
-                }
-                Command::SetPlotInfo(info) => {
-                    self.plot_info = info;
-                }
-                Command::DeleteBipole { name } => {
-                    let bipole = self.placed_bipoles.get(&name).unwrap();
-                    self.nodes.remove(&bipole.anode_node_id);
-                    self.nodes.remove(&bipole.catode_node_id);
-                    self.placed_bipoles.remove(&name);
-                }
-                Command::DeleteWire { id } => {
-                    let wire = self.wires.get(&id).unwrap();
-                    self.nodes.remove(&wire.node1_id);
-                    self.nodes.remove(&wire.node2_id);
-                    self.wires.remove(&id);
-                }
-                Command::SetGround(id) => {
-                    self.ground_id = Some(id);
+            }
+            Command::RunSimulation { sim_time, t_step } => {
+                self.run(sim_time, t_step);
+            }
+            Command::ExportNetlist { path } => {
+                self.status = Some(match self.export_netlist(&path) {
+                    Ok(()) => Status::Done(format!("exported netlist to {path}")),
+                    Err(error) => Status::Failed(format!("could not export netlist to {path}: {error}"))
+                });
+            }
+            Command::SaveSchematic { path } => {
+                self.status = Some(match self.save(&path) {
+                    Ok(()) => Status::Done(format!("saved schematic to {path}")),
+                    Err(error) => Status::Failed(format!("could not save schematic to {path}: {error}"))
+                });
+            }
+            Command::OpenSchematic { path } => {
+                self.status = Some(match self.open(&path) {
+                    Ok(()) => Status::Done(format!("opened schematic {path}")),
+                    Err(error) => Status::Failed(format!("could not open schematic {path}: {error}"))
+                });
+            }
+            Command::SetPlotInfo(info) => {
+                self.plot_info = info;
+            }
+            Command::DeleteBipole { name } => {
+                let bipole = self.placed_bipoles.get(&name).unwrap();
+                self.nodes.remove(&bipole.anode_node_id);
+                self.nodes.remove(&bipole.catode_node_id);
+                for node_id in &bipole.extra_node_ids {
+                    self.nodes.remove(node_id);
                 }
+                self.placed_bipoles.remove(&name);
+            }
+            Command::DeleteWire { id } => {
+                let wire = self.wires.get(&id).unwrap();
+                self.nodes.remove(&wire.node1_id);
+                self.nodes.remove(&wire.node2_id);
+                self.wires.remove(&id);
+            }
+            Command::SetGround(id) => {
+                self.ground_id = Some(id);
             }
         }
     }
@@ -1130,10 +1781,18 @@ impl  UiData {
             //draw_text(name, x+15.0, y-15.0, 15.0, BLACK);
             draw_name(name, bipole.center_position, bipole.rotation);
             draw_plus(bipole.size, bipole.center_position, bipole.rotation);
+
+            if self.highlighted_bipoles.contains(name) {
+                let rect = bipole.rotation.get_rect(bipole.size, bipole.center_position);
+                draw_rectangle_lines(rect.x, rect.y, rect.w, rect.h, 2.0, RED);
+            }
         }
 
         for (_, node) in &self.nodes {
             let (x, y) = (node.position.x, node.position.y);
+            if self.highlighted_nets.contains(&node.computed_id) {
+                draw_circle(x, y, 4.0, RED);
+            }
             if node.number_connected <= 2 {continue;}
             draw_circle(x, y, 2.0, BLACK);
         }
@@ -1144,6 +1803,14 @@ impl  UiData {
 
             draw_line(x1, y1, x2, y2, 1.0, BLACK);
         }
+
+        if let Some(status) = &self.status {
+            let (text, color) = match status {
+                Status::Done(text) => (text, DARKGREEN),
+                Status::Failed(text) => (text, RED)
+            };
+            draw_text(text, 20.0, screen_height() - 20.0, 20.0, color);
+        }
     }
 
 
@@ -1158,6 +1825,9 @@ enum ToolBarEvent {
     MeasureClicked,
     DeleteClicked,
     SetGroundClicked,
+    ExportClicked,
+    SaveClicked,
+    OpenClicked,
     NoneClicked
 
 }
@@ -1172,7 +1842,8 @@ enum ClickEvent {
 }
 
 struct UiInfo {
-    current_node_id: usize
+    current_node_id: usize,
+    schematic_path: Option<String>
 }
 
 #[macroquad::main("UI Circuit sim")]
@@ -1185,7 +1856,15 @@ async fn main() {
         (String::from("capacitor"), load_texture("assets/capacitor.png").await.unwrap()),
         (String::from("voltage source"), load_texture("assets/voltage_source.png").await.unwrap()),
         (String::from("current source"), load_texture("assets/current_source.png").await.unwrap()),
-        (String::from("sinusoidal"), load_texture("assets/sinusoidal.png").await.unwrap()),]);
+        (String::from("sinusoidal"), load_texture("assets/sinusoidal.png").await.unwrap()),
+        (String::from("sinusoidal current"), load_texture("assets/sinusoidal_current.png").await.unwrap()),
+        (String::from("vcvs"), load_texture("assets/vcvs.png").await.unwrap()),
+        (String::from("vccs"), load_texture("assets/vccs.png").await.unwrap()),
+        (String::from("npn"), load_texture("assets/npn.png").await.unwrap()),
+        (String::from("pnp"), load_texture("assets/pnp.png").await.unwrap()),
+        (String::from("nmos"), load_texture("assets/nmos.png").await.unwrap()),
+        (String::from("pmos"), load_texture("assets/pmos.png").await.unwrap()),
+        (String::from("opamp"), load_texture("assets/opamp.png").await.unwrap()),]);
     let mut uidata = UiData::new();
     let toolbar_rect = Rect::new(20.0, 0.0, screen_width()-40.0, 50.0);
 
@@ -1229,6 +1908,21 @@ async fn main() {
                     if ui.button(vec2(600.0, 0.0), "Set ground") {
                         toolbar_event = ToolBarEvent::SetGroundClicked;
                     }
+                    ui.same_line(0.);
+
+                    if ui.button(vec2(700.0, 0.0), "Export netlist") {
+                        toolbar_event = ToolBarEvent::ExportClicked;
+                    }
+                    ui.same_line(0.);
+
+                    if ui.button(vec2(820.0, 0.0), "Save") {
+                        toolbar_event = ToolBarEvent::SaveClicked;
+                    }
+                    ui.same_line(0.);
+
+                    if ui.button(vec2(870.0, 0.0), "Open") {
+                        toolbar_event = ToolBarEvent::OpenClicked;
+                    }
                 
 
             });
@@ -1241,3 +1935,324 @@ async fn main() {
         next_frame().await;
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn place(uidata: &mut UiData, kind: &str, center_position: Vec2, rotation: BipoleRotation) {
+        uidata.add_bipole(&BipoleToPlace {
+            size: vec2(80.0, 20.0),
+            center_position,
+            rotation,
+            kind: String::from(kind)
+        });
+    }
+
+    #[test]
+    fn test_export_netlist() {
+        let mut uidata = UiData::new();
+        place(&mut uidata, "voltage source", vec2(100.0, 200.0), BipoleRotation::AnodeUp);
+        place(&mut uidata, "resistor", vec2(200.0, 100.0), BipoleRotation::AnodeLeft);
+        place(&mut uidata, "inductor", vec2(300.0, 200.0), BipoleRotation::AnodeUp);
+
+        // v1 anode to r2 catode, r2 anode to i3 anode, both bottoms to ground
+        uidata.add_wire(1, 4);
+        uidata.add_wire(3, 5);
+        uidata.add_wire(2, 6);
+        uidata.ground_id = Some(2);
+        uidata.last_run = Some((1.0, 0.5));
+
+        let text = uidata.netlist_text();
+        assert!(text.contains("v1 1 0 DC 1e1\n"));
+        assert!(text.contains("Li3 2 0 2e-5\n"));
+        assert!(text.ends_with(".tran 5e-1 1e0\n.end\n"));
+
+        let parsed = netlist::parse(&text).unwrap();
+        assert_eq!(parsed.nodes.len(), 3);
+    }
+
+    #[test]
+    fn test_export_netlist_status() {
+        assert_eq!(default_netlist_path(None), "netlist.cir");
+        assert_eq!(default_netlist_path(Some("designs/filter.json")), "designs/filter.cir");
+
+        let mut uidata = UiData::new();
+        place(&mut uidata, "resistor", vec2(100.0, 200.0), BipoleRotation::AnodeUp);
+        let path = std::env::temp_dir().join(format!("export_{}.cir", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        uidata.execute(Command::ExportNetlist { path: path.clone() });
+        assert!(matches!(&uidata.status, Some(Status::Done(text)) if text.ends_with(&path)));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), uidata.netlist_text());
+        std::fs::remove_file(&path).unwrap();
+
+        let missing = std::env::temp_dir().join("no such directory").join("netlist.cir");
+        uidata.execute(Command::ExportNetlist { path: missing.to_string_lossy().into_owned() });
+        assert!(matches!(uidata.status, Some(Status::Failed(_))));
+    }
+
+    #[test]
+    fn test_sinusoidal_current_source() {
+        let mut uidata = UiData::new();
+        place(&mut uidata, "sinusoidal current", vec2(100.0, 200.0), BipoleRotation::AnodeUp);
+        place(&mut uidata, "resistor", vec2(200.0, 200.0), BipoleRotation::AnodeUp);
+        uidata.add_wire(1, 3);
+        uidata.add_wire(2, 4);
+        uidata.ground_id = Some(2);
+
+        // a quarter period into the sine the whole amplitude is drawn out of the top node
+        uidata.run(1.0, 0.25);
+        let output = uidata.simulation_output.as_ref().unwrap();
+        let top_id = uidata.nodes.get(&1).unwrap().computed_id;
+        assert!((output.node_voltages.get(&top_id).unwrap()[1] + 1e-2).abs() < 1e-12);
+
+        let text = uidata.netlist_text();
+        assert!(text.contains("Is1 1 0 SIN(0 1e-3 1e0) AC 0e0 0e0\n"));
+        assert!(netlist::parse(&text).is_ok());
+
+        // the AC attribute reaches both the netlist and the small-signal analysis
+        let source = uidata.placed_bipoles.get_mut("s1").unwrap();
+        source.factory.set_parameter("ac", 1e-3);
+        source.factory.set_parameter("ac_phase", -90.0);
+        let text = uidata.netlist_text();
+        assert!(text.contains("Is1 1 0 SIN(0 1e-3 1e0) AC 1e-3 -9e1\n"));
+        let parsed = netlist::parse(&text).unwrap();
+        let mut circuit = parsed.circuit;
+        let out = circuit.ac_sweep(bipoles::Sweep::Linear, 1, 1.0, 1.0).unwrap();
+        let voltage = out.node_voltages.get(parsed.nodes.get("1").unwrap()).unwrap()[0];
+        assert!(voltage.re.abs() < 1e-12 && (voltage.im - 1e-2).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_topology_highlight() {
+        let mut uidata = UiData::new();
+        place(&mut uidata, "voltage source", vec2(100.0, 200.0), BipoleRotation::AnodeUp);
+        place(&mut uidata, "voltage source", vec2(200.0, 200.0), BipoleRotation::AnodeUp);
+        place(&mut uidata, "resistor", vec2(300.0, 200.0), BipoleRotation::AnodeUp);
+
+        // two sources in parallel with the resistor
+        uidata.add_wire(1, 3);
+        uidata.add_wire(3, 5);
+        uidata.add_wire(2, 4);
+        uidata.add_wire(4, 6);
+        uidata.ground_id = Some(2);
+
+        uidata.run(1.0, 0.1);
+        assert!(uidata.simulation_output.is_none());
+        uidata.highlighted_bipoles.sort();
+        assert_eq!(uidata.highlighted_bipoles, vec![String::from("v1"), String::from("v2")]);
+        assert!(uidata.highlighted_nets.contains(&uidata.nodes.get(&1).unwrap().computed_id));
+
+        uidata.placed_bipoles.remove("v2");
+        uidata.run(1.0, 0.1);
+        assert!(uidata.simulation_output.is_some());
+        assert!(uidata.highlighted_bipoles.is_empty());
+    }
+
+    #[test]
+    fn test_controlled_source() {
+        let mut uidata = UiData::new();
+        place(&mut uidata, "vcvs", vec2(200.0, 200.0), BipoleRotation::AnodeUp);
+        place(&mut uidata, "voltage source", vec2(100.0, 200.0), BipoleRotation::AnodeUp);
+        place(&mut uidata, "resistor", vec2(300.0, 200.0), BipoleRotation::AnodeUp);
+        assert_eq!(uidata.placed_bipoles.get("v1").unwrap().extra_node_ids, vec![3, 4]);
+        assert_eq!(uidata.nodes.get(&3).unwrap().position, vec2(180.0, 220.0));
+
+        // the source drives the controlling pins, the output feeds the resistor
+        uidata.add_wire(5, 3);
+        uidata.add_wire(6, 4);
+        uidata.add_wire(1, 7);
+        uidata.add_wire(2, 8);
+        uidata.add_wire(2, 6);
+        uidata.ground_id = Some(6);
+
+        uidata.run(1.0, 0.5);
+        let output = uidata.simulation_output.as_ref().unwrap();
+        let out_id = uidata.nodes.get(&7).unwrap().computed_id;
+        assert!((output.node_voltages.get(&out_id).unwrap()[0] - 100.0).abs() < 1e-9);
+
+        let text = uidata.netlist_text();
+        assert!(text.contains("Ev1 1 0 2 0 1e1\n"));
+
+        let data = uidata.to_schematic();
+        let loaded = UiData::from_schematic(schematic::Schematic::from_json(&data.to_json()).unwrap()).unwrap();
+        assert_eq!(loaded.to_schematic(), data);
+        assert_eq!(loaded.nodes.get(&3).unwrap().number_connected, 2);
+    }
+
+    #[test]
+    fn test_transistor() {
+        let mut uidata = UiData::new();
+        place(&mut uidata, "npn", vec2(200.0, 200.0), BipoleRotation::AnodeUp);
+        place(&mut uidata, "voltage source", vec2(100.0, 200.0), BipoleRotation::AnodeUp);
+        place(&mut uidata, "resistor", vec2(300.0, 200.0), BipoleRotation::AnodeUp);
+        place(&mut uidata, "resistor", vec2(400.0, 200.0), BipoleRotation::AnodeUp);
+        assert_eq!(uidata.placed_bipoles.get("n1").unwrap().extra_node_ids, vec![3]);
+        uidata.placed_bipoles.get_mut("r3").unwrap().factory.set_parameter("resistance", 1e6);
+        uidata.placed_bipoles.get_mut("r4").unwrap().factory.set_parameter("resistance", 1e3);
+
+        // base fed through r3, collector load r4, emitter to ground
+        uidata.add_wire(4, 6);
+        uidata.add_wire(7, 3);
+        uidata.add_wire(4, 8);
+        uidata.add_wire(9, 1);
+        uidata.add_wire(2, 5);
+        uidata.ground_id = Some(5);
+
+        uidata.run(1.0, 0.5);
+        let output = uidata.simulation_output.as_ref().unwrap();
+        let gain = output.currents.get("n1.c").unwrap()[0]/output.currents.get("n1.b").unwrap()[0];
+        assert!((gain - 100.0).abs() < 1.0);
+
+        let text = uidata.netlist_text();
+        assert!(text.contains("Qn1 1 2 0 Qn1_model\n.model Qn1_model NPN(IS=1e-16 BF=1e2)\n"));
+        assert!(netlist::parse(&text).is_ok());
+    }
+
+    #[test]
+    fn test_mosfet_switch() {
+        let mut uidata = UiData::new();
+        place(&mut uidata, "nmos", vec2(200.0, 200.0), BipoleRotation::AnodeUp);
+        place(&mut uidata, "voltage source", vec2(100.0, 200.0), BipoleRotation::AnodeUp);
+        place(&mut uidata, "resistor", vec2(300.0, 200.0), BipoleRotation::AnodeUp);
+        uidata.placed_bipoles.get_mut("r3").unwrap().factory.set_parameter("resistance", 1e4);
+
+        // gate and drain load on the supply, source to ground
+        uidata.add_wire(4, 6);
+        uidata.add_wire(7, 1);
+        uidata.add_wire(4, 3);
+        uidata.add_wire(2, 5);
+        uidata.ground_id = Some(5);
+
+        uidata.run(1.0, 0.5);
+        let output = uidata.simulation_output.as_ref().unwrap();
+        let drain_id = uidata.nodes.get(&1).unwrap().computed_id;
+        let voltage_ds = output.node_voltages.get(&drain_id).unwrap()[0];
+        assert!(voltage_ds > 0.0 && voltage_ds < 0.2);
+        assert_eq!(output.currents.get("n1.g").unwrap()[0], 0.0);
+
+        let text = uidata.netlist_text();
+        assert!(text.contains("Mn1 1 2 0 0 Mn1_model W=1e-5 L=1e-6\n.model Mn1_model NMOS(VTO=7e-1 KP=1e-4)\n"));
+        assert!(netlist::parse(&text).is_ok());
+    }
+
+    #[test]
+    fn test_opamp() {
+        let mut uidata = UiData::new();
+        place(&mut uidata, "opamp", vec2(200.0, 200.0), BipoleRotation::AnodeUp);
+        place(&mut uidata, "voltage source", vec2(100.0, 200.0), BipoleRotation::AnodeUp);
+        place(&mut uidata, "resistor", vec2(300.0, 200.0), BipoleRotation::AnodeUp);
+        place(&mut uidata, "resistor", vec2(400.0, 200.0), BipoleRotation::AnodeUp);
+        uidata.placed_bipoles.get_mut("v2").unwrap().factory.set_parameter("value", 1.0);
+        uidata.placed_bipoles.get_mut("r4").unwrap().factory.set_parameter("resistance", 20.0);
+
+        // inverting amplifier with a gain of -2
+        uidata.add_wire(4, 6);
+        uidata.add_wire(7, 2);
+        uidata.add_wire(2, 8);
+        uidata.add_wire(9, 1);
+        uidata.add_wire(3, 5);
+        uidata.ground_id = Some(5);
+
+        uidata.run(1.0, 0.5);
+        let output = uidata.simulation_output.as_ref().unwrap();
+        let out_id = uidata.nodes.get(&1).unwrap().computed_id;
+        assert!((output.node_voltages.get(&out_id).unwrap()[1] + 2.0).abs() < 1e-3);
+
+        // the exported pole rolls the closed-loop gain off as the op-amp's does: at 100 kHz the
+        // open-loop gain is 1e5/(1 + 1e4 j), and the loop gain a third of it
+        let text = uidata.netlist_text();
+        assert!(text.contains("* o1: the rails at -1.5e1 and 1.5e1 V are not exported\nGo1 0 o1_pole 0 2 1e5\n\
+            Ro1 o1_pole 0 1\nCo1 o1_pole 0 1.5915494309189534e-2\nEo1 1 0 o1_pole 0 1\n"));
+        let parsed = netlist::parse(&text.replace("v2 3 0 DC 1e0", "v2 3 0 DC 1e0 AC 1")).unwrap();
+        let mut circuit = parsed.circuit;
+        assert!((circuit.operating_point().unwrap().voltage("1").unwrap() + 2.0).abs() < 1e-3);
+        let out = circuit.ac_sweep(bipoles::Sweep::Linear, 1, 1e5, 1e5).unwrap();
+        let voltage = out.node_voltages.get(parsed.nodes.get("1").unwrap()).unwrap()[0];
+        let expected = 2.0/((1.0f64 + 3e-5).powi(2) + 0.3f64.powi(2)).sqrt();
+        assert!(((voltage.re.powi(2) + voltage.im.powi(2)).sqrt() - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_schematic_round_trip() {
+        let mut uidata = UiData::new();
+        place(&mut uidata, "diode", vec2(100.0, 200.0), BipoleRotation::AnodeDown);
+        place(&mut uidata, "capacitor", vec2(200.0, 100.0), BipoleRotation::AnodeLeft);
+        uidata.add_wire(1, 4);
+        uidata.ground_id = Some(2);
+        uidata.placed_bipoles.get_mut("c2").unwrap().factory.set_parameter("capacitance", 1e-6);
+
+        let data = uidata.to_schematic();
+        let mut loaded = UiData::from_schematic(schematic::Schematic::from_json(&data.to_json()).unwrap()).unwrap();
+
+        assert_eq!(loaded.to_schematic(), data);
+        assert_eq!(loaded.current_node_id, 4);
+        assert_eq!(loaded.current_bipole_id, 2);
+        assert_eq!(loaded.nodes.get(&4).unwrap().number_connected, 2);
+        assert_eq!(loaded.netlist_text(), uidata.netlist_text());
+    }
+
+    #[test]
+    fn test_save_open_status() {
+        let mut uidata = UiData::new();
+        place(&mut uidata, "resistor", vec2(100.0, 200.0), BipoleRotation::AnodeUp);
+        let path = std::env::temp_dir().join(format!("schematic_{}.json", std::process::id()));
+        let path = path.to_string_lossy().into_owned();
+        uidata.execute(Command::SaveSchematic { path: path.clone() });
+        assert!(matches!(uidata.status, Some(Status::Done(_))));
+        assert_eq!(uidata.schematic_path.as_deref(), Some(path.as_str()));
+
+        let mut opened = UiData::new();
+        opened.execute(Command::OpenSchematic { path: path.clone() });
+        assert!(matches!(opened.status, Some(Status::Done(_))));
+        assert_eq!(opened.to_schematic(), uidata.to_schematic());
+        assert_eq!(opened.schematic_path.as_deref(), Some(path.as_str()));
+
+        // a file this editor cannot load is reported and leaves the open schematic alone
+        let mut data = uidata.to_schematic();
+        data.version += 1;
+        data.save(&path).unwrap();
+        opened.execute(Command::OpenSchematic { path: path.clone() });
+        assert!(matches!(&opened.status, Some(Status::Failed(text)) if text.contains("newer than the supported")));
+        assert_eq!(opened.placed_bipoles.len(), 1);
+
+        data.version -= 1;
+        data.bipoles[0].kind = String::from("flux capacitor");
+        data.save(&path).unwrap();
+        opened.execute(Command::OpenSchematic { path: path.clone() });
+        assert!(matches!(&opened.status, Some(Status::Failed(text)) if text.contains("unknown kind")));
+        std::fs::remove_file(&path).unwrap();
+
+        opened.execute(Command::OpenSchematic { path });
+        assert!(matches!(&opened.status, Some(Status::Failed(text)) if text.contains("could not access")));
+    }
+
+    #[test]
+    fn test_schematic_unknown_kind() {
+        let mut uidata = UiData::new();
+        place(&mut uidata, "resistor", vec2(100.0, 200.0), BipoleRotation::AnodeUp);
+
+        let mut data = uidata.to_schematic();
+        data.bipoles[0].kind = String::from("flux capacitor");
+
+        assert!(matches!(UiData::from_schematic(data),
+            Err(schematic::SchematicError::UnknownKind { name, kind }) if name == "r1" && kind == "flux capacitor"));
+    }
+
+    #[test]
+    fn test_schematic_pin_count() {
+        let mut uidata = UiData::new();
+        place(&mut uidata, "vcvs", vec2(100.0, 200.0), BipoleRotation::AnodeUp);
+
+        // an older or hand-edited file may lack the controlling pins
+        let mut data = uidata.to_schematic();
+        data.bipoles[0].extra_node_ids.truncate(1);
+        let text = data.to_json();
+        assert!(matches!(UiData::from_schematic(schematic::Schematic::from_json(&text).unwrap()),
+            Err(schematic::SchematicError::PinCount { name, expected: 2, found: 1, .. }) if name == "v1"));
+
+        data.bipoles[0].extra_node_ids.clear();
+        assert!(matches!(UiData::from_schematic(data), Err(schematic::SchematicError::PinCount { found: 0, .. })));
+    }
+}
@@ -0,0 +1,1197 @@
+//! Parser for SPICE-style netlists.
+//!
+//! The first line of a deck is its title. Element cards (R, C, L, K, V, I, D, Q, M, S, T and the
+//! controlled sources E, F, G, H), `.model` cards for diodes, transistors and switches, `.tran`, `.op`, `.ac`, `.options` and `.end` are understood; lines starting
+//! with `*` are comments, `;` starts an inline comment and a line starting with
+//! `+` continues the previous card. Independent sources take a DC value, an AC value and
+//! one of the SIN, PULSE, PWL (or `PWL FILE=path` for CSV data), EXP and SFFM waveforms;
+//! with a waveform, the DC value only sets the operating point and the bias of `.ac`.
+//! Two inductors coupled by a K card become one device named after it, whose winding
+//! currents are reported under its `p1` and `p2` terminals.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::bipoles;
+use crate::magnetics;
+use crate::switches;
+use crate::transistors;
+use crate::transmission_lines;
+use crate::waveforms;
+
+pub const THERMAL_VOLTAGE: f64 = 25.852e-3;
+const DEFAULT_SATURATION_CURRENT: f64 = 1.0e-14;
+
+pub enum Analysis {
+    Transient { step_sec: f64, stop_sec: f64 },
+    OperatingPoint,
+    Ac { sweep: bipoles::Sweep, points: usize, start_hz: f64, stop_hz: f64 }
+}
+
+pub struct Netlist {
+    pub title: String,
+    pub circuit: bipoles::Circuit,
+    pub nodes: HashMap<String, usize>,
+    pub analyses: Vec<Analysis>,
+    pub options: bipoles::SimulationOptions
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String
+}
+
+impl ParseError {
+    fn new(line: usize, column: usize, message: String) -> ParseError {
+        ParseError { line, column, message }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    column: usize
+}
+
+impl<'a> Token<'a> {
+    fn error(&self, message: String) -> ParseError {
+        ParseError::new(self.line, self.column, message)
+    }
+
+    fn lowercase(&self) -> String {
+        self.text.to_lowercase()
+    }
+
+    fn value(&self) -> Result<f64, ParseError> {
+        parse_value(self.text).ok_or_else(|| self.error(format!("invalid number '{}'", self.text)))
+    }
+}
+
+struct Card<'a> {
+    tokens: Vec<Token<'a>>,
+    end_line: usize,
+    end_column: usize
+}
+
+impl<'a> Card<'a> {
+    fn get(&self, index: usize, what: &str) -> Result<Token<'a>, ParseError> {
+        self.tokens.get(index).copied()
+            .ok_or_else(|| ParseError::new(self.end_line, self.end_column, format!("missing {what}")))
+    }
+
+    fn name(&self) -> &'a str {
+        self.tokens[0].text
+    }
+}
+
+struct DiodeModel {
+    current_s: f64,
+    emission_coefficient: f64
+}
+
+enum Model {
+    Diode(DiodeModel),
+    Bjt(transistors::Polarity, transistors::BjtParameters),
+    Mosfet(transistors::Channel, transistors::MosfetParameters),
+    Switch(switches::SwitchParameters)
+}
+
+/// Parses a whole deck into a circuit, with node `0` (or `gnd`) as ground.
+pub fn parse(source: &str) -> Result<Netlist, ParseError> {
+    let mut lines = source.lines();
+    let title = match lines.next() {
+        Some(line) => String::from(line.trim()),
+        None => return Err(ParseError::new(1, 1, String::from("empty netlist")))
+    };
+
+    let cards = split_cards(source)?;
+
+    let mut models = HashMap::new();
+    for card in &cards {
+        if card.name().eq_ignore_ascii_case(".model") {
+            let (name, model) = parse_model(card)?;
+            models.insert(name, model);
+        }
+    }
+
+    let mut parser = Parser {
+        circuit: bipoles::Circuit::new(0),
+        nodes: HashMap::new(),
+        names: HashMap::new(),
+        current_controls: Vec::new(),
+        inductors: Vec::new(),
+        couplings: Vec::new(),
+        models,
+        analyses: Vec::new(),
+        options: bipoles::SimulationOptions::default()
+    };
+
+    let mut last_line = 1;
+    for card in &cards {
+        last_line = card.end_line;
+        let name = card.name();
+        if name.eq_ignore_ascii_case(".end") {
+            break;
+        }
+        if name.starts_with('.') {
+            parser.parse_control(card)?;
+        } else {
+            parser.parse_element(card)?;
+        }
+    }
+    parser.add_current_controlled()?;
+    parser.add_inductors()?;
+
+    if !parser.nodes.values().any(|id| *id == 0) {
+        return Err(ParseError::new(last_line, 1, String::from("netlist has no ground node '0'")));
+    }
+
+    Ok(Netlist { title, circuit: parser.circuit, nodes: parser.nodes, analyses: parser.analyses, options: parser.options })
+}
+
+/// Parses a number with an optional engineering suffix (`f`, `p`, `n`, `u`,
+/// `m`, `k`, `meg`, `g`, `t`, `mil`); trailing unit letters are ignored.
+fn parse_value(text: &str) -> Option<f64> {
+    let lower = text.to_lowercase();
+    let bytes = lower.as_bytes();
+    let mut end = 0;
+
+    if end < bytes.len() && (bytes[end] == b'+' || bytes[end] == b'-') {
+        end += 1;
+    }
+    while end < bytes.len() && (bytes[end].is_ascii_digit() || bytes[end] == b'.') {
+        end += 1;
+    }
+    if end < bytes.len() && bytes[end] == b'e' {
+        let mut exponent_end = end + 1;
+        if exponent_end < bytes.len() && (bytes[exponent_end] == b'+' || bytes[exponent_end] == b'-') {
+            exponent_end += 1;
+        }
+        if exponent_end < bytes.len() && bytes[exponent_end].is_ascii_digit() {
+            while exponent_end < bytes.len() && bytes[exponent_end].is_ascii_digit() {
+                exponent_end += 1;
+            }
+            end = exponent_end;
+        }
+    }
+
+    let mantissa: f64 = lower[..end].parse().ok()?;
+    let suffix = &lower[end..];
+    if !suffix.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let scale = if suffix.starts_with("meg") {
+        1e6
+    } else if suffix.starts_with("mil") {
+        25.4e-6
+    } else {
+        match suffix.chars().next() {
+            Some('f') => 1e-15,
+            Some('p') => 1e-12,
+            Some('n') => 1e-9,
+            Some('u') => 1e-6,
+            Some('m') => 1e-3,
+            Some('k') => 1e3,
+            Some('g') => 1e9,
+            Some('t') => 1e12,
+            _ => 1.0
+        }
+    };
+
+    Some(mantissa * scale)
+}
+
+fn split_cards(source: &str) -> Result<Vec<Card<'_>>, ParseError> {
+    let mut cards: Vec<Card> = Vec::new();
+
+    for (index, line) in source.lines().enumerate().skip(1) {
+        let line_number = index + 1;
+        let line = match line.find(';') {
+            Some(position) => &line[..position],
+            None => line
+        };
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('*') {
+            continue;
+        }
+
+        let is_continuation = trimmed.starts_with('+');
+        let mut tokens = tokenize(line, line_number);
+        let end_column = line.trim_end().chars().count() + 1;
+
+        if is_continuation {
+            tokens.remove(0);
+            match cards.last_mut() {
+                Some(card) => {
+                    card.tokens.extend(tokens);
+                    card.end_line = line_number;
+                    card.end_column = end_column;
+                }
+                None => {
+                    let column = line.len() - trimmed.len() + 1;
+                    return Err(ParseError::new(line_number, column,
+                        String::from("continuation line without a card to continue")));
+                }
+            }
+        } else if !tokens.is_empty() {
+            cards.push(Card { tokens, end_line: line_number, end_column });
+        }
+    }
+
+    Ok(cards)
+}
+
+fn tokenize(line: &str, line_number: usize) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start: Option<(usize, usize)> = None;
+
+    for (column, (position, c)) in line.char_indices().enumerate() {
+        let is_separator = c.is_whitespace() || c == '(' || c == ')' || c == ',' || c == '=';
+        let is_plus = c == '+' && tokens.is_empty() && start.is_none();
+
+        if is_separator || is_plus {
+            if let Some((begin, begin_column)) = start.take() {
+                tokens.push(Token { text: &line[begin..position], line: line_number, column: begin_column });
+            }
+            if is_plus {
+                tokens.push(Token { text: "+", line: line_number, column: column + 1 });
+            }
+        } else if start.is_none() {
+            start = Some((position, column + 1));
+        }
+    }
+    if let Some((begin, begin_column)) = start {
+        tokens.push(Token { text: &line[begin..], line: line_number, column: begin_column });
+    }
+
+    tokens
+}
+
+fn parse_model(card: &Card) -> Result<(String, Model), ParseError> {
+    let name = card.get(1, "model name")?;
+    let kind = card.get(2, "model type")?;
+    let mut model = match kind.lowercase().as_str() {
+        "d" => Model::Diode(DiodeModel { current_s: DEFAULT_SATURATION_CURRENT, emission_coefficient: 1.0 }),
+        "npn" => Model::Bjt(transistors::Polarity::Npn, transistors::BjtParameters::default()),
+        "pnp" => Model::Bjt(transistors::Polarity::Pnp, transistors::BjtParameters::default()),
+        "nmos" => Model::Mosfet(transistors::Channel::N, transistors::MosfetParameters::default()),
+        "pmos" => Model::Mosfet(transistors::Channel::P, transistors::MosfetParameters::default()),
+        "sw" => Model::Switch(switches::SwitchParameters::default()),
+        _ => return Err(kind.error(format!("unsupported model type '{}'", kind.text)))
+    };
+
+    let mut index = 3;
+    while index < card.tokens.len() {
+        let parameter = card.tokens[index];
+        let value = card.get(index + 1, &format!("value for parameter '{}'", parameter.text))?.value()?;
+        match (&mut model, parameter.lowercase().as_str()) {
+            (Model::Diode(diode), "is") => diode.current_s = value,
+            (Model::Diode(diode), "n") => diode.emission_coefficient = value,
+            (Model::Diode(_), _) => return Err(parameter.error(format!("unsupported diode parameter '{}'", parameter.text))),
+            (Model::Bjt(_, bjt), "is") => bjt.current_s = value,
+            (Model::Bjt(_, bjt), "bf") => bjt.beta_f = value,
+            (Model::Bjt(_, bjt), "br") => bjt.beta_r = value,
+            (Model::Bjt(_, bjt), "vaf") => bjt.early_voltage = value,
+            (Model::Bjt(_, bjt), "cje") => bjt.capacitance_je = value,
+            (Model::Bjt(_, bjt), "cjc") => bjt.capacitance_jc = value,
+            (Model::Mosfet(_, mosfet), "vto") => mosfet.threshold_voltage = value,
+            (Model::Mosfet(_, mosfet), "kp") => mosfet.transconductance = value,
+            (Model::Mosfet(_, mosfet), "lambda") => mosfet.channel_length_modulation = value,
+            (Model::Mosfet(_, mosfet), "gamma") => mosfet.body_effect = value,
+            (Model::Mosfet(_, mosfet), "phi") => mosfet.surface_potential = value,
+            (Model::Mosfet(_, mosfet), "cgso") => mosfet.overlap_capacitance_gs = value,
+            (Model::Mosfet(_, mosfet), "cgdo") => mosfet.overlap_capacitance_gd = value,
+            (Model::Bjt(..) | Model::Mosfet(..), _) =>
+                return Err(parameter.error(format!("unsupported transistor parameter '{}'", parameter.text))),
+            (Model::Switch(switch), "vt") => switch.threshold = value,
+            (Model::Switch(switch), "vh") => switch.hysteresis = value,
+            (Model::Switch(switch), "ron") => switch.on_resistance = value,
+            (Model::Switch(switch), "roff") => switch.off_resistance = value,
+            (Model::Switch(_), _) => return Err(parameter.error(format!("unsupported switch parameter '{}'", parameter.text)))
+        }
+        index += 2;
+    }
+
+    Ok((name.lowercase(), model))
+}
+
+/// `F` and `H` elements, added once every voltage source they may sense has been read.
+struct CurrentControl {
+    behaviour: Box<dyn bipoles::BipoleBehaviour>,
+    anode_id: usize,
+    catode_id: usize,
+    name: String,
+    source: String,
+    line: usize,
+    column: usize
+}
+
+/// `L` elements, added once every `K` card that may couple them has been read.
+struct PendingInductor {
+    name: String,
+    anode_id: usize,
+    catode_id: usize,
+    induttance: f64,
+    initial_i: f64
+}
+
+/// `Kname Lprimary Lsecondary coefficient`, with the position of each inductor name.
+struct Coupling {
+    name: String,
+    inductors: [(String, usize, usize); 2],
+    coefficient: f64
+}
+
+struct Parser {
+    circuit: bipoles::Circuit,
+    nodes: HashMap<String, usize>,
+    /// Element names as written, by their lowercase form.
+    names: HashMap<String, String>,
+    current_controls: Vec<CurrentControl>,
+    inductors: Vec<PendingInductor>,
+    couplings: Vec<Coupling>,
+    models: HashMap<String, Model>,
+    analyses: Vec<Analysis>,
+    options: bipoles::SimulationOptions
+}
+
+impl Parser {
+    fn node(&mut self, token: Token) -> usize {
+        let name = token.lowercase();
+        let name = if name == "gnd" { String::from("0") } else { name };
+        let id = self.circuit.net(&name);
+        self.nodes.insert(name, id);
+        id
+    }
+
+    fn parse_control(&mut self, card: &Card) -> Result<(), ParseError> {
+        let command = card.tokens[0];
+        match command.lowercase().as_str() {
+            ".tran" => {
+                let step_sec = card.get(1, "time step")?.value()?;
+                let stop_sec = card.get(2, "stop time")?.value()?;
+                if let Some(extra) = card.tokens.iter().skip(3).find(|token| !token.text.eq_ignore_ascii_case("uic")) {
+                    return Err(extra.error(format!("unsupported .tran argument '{}'", extra.text)));
+                }
+                if step_sec <= 0.0 || stop_sec <= 0.0 {
+                    return Err(command.error(String::from(".tran step and stop time must be positive")));
+                }
+                self.analyses.push(Analysis::Transient { step_sec, stop_sec });
+            }
+            ".op" => {
+                expect_end(card, 1)?;
+                self.analyses.push(Analysis::OperatingPoint);
+            }
+            ".ac" => {
+                let sweep_token = card.get(1, "sweep type")?;
+                let sweep = match sweep_token.lowercase().as_str() {
+                    "lin" => bipoles::Sweep::Linear,
+                    "dec" => bipoles::Sweep::Decade,
+                    "oct" => bipoles::Sweep::Octave,
+                    _ => return Err(sweep_token.error(format!("unknown sweep type '{}'", sweep_token.text)))
+                };
+                let points_token = card.get(2, "number of points")?;
+                let points = points_token.value()?;
+                if points < 1.0 || points.fract() != 0.0 {
+                    return Err(points_token.error(String::from("number of points must be a positive integer")));
+                }
+                let start_hz = card.get(3, "start frequency")?.value()?;
+                let stop_token = card.get(4, "stop frequency")?;
+                let stop_hz = stop_token.value()?;
+                expect_end(card, 5)?;
+                if start_hz <= 0.0 || stop_hz < start_hz {
+                    return Err(stop_token.error(String::from("frequencies must be positive and increasing")));
+                }
+                self.analyses.push(Analysis::Ac { sweep, points: points as usize, start_hz, stop_hz });
+            }
+            ".options" | ".option" => {
+                let mut index = 1;
+                while index < card.tokens.len() {
+                    let option = card.tokens[index];
+                    let value = card.get(index + 1, &format!("value for option '{}'", option.text))?;
+                    match option.lowercase().as_str() {
+                        "method" => {
+                            self.options.method = match value.lowercase().as_str() {
+                                "euler" => bipoles::IntegrationMethod::BackwardEuler,
+                                "trap" | "trapezoidal" => bipoles::IntegrationMethod::Trapezoidal,
+                                "gear" => bipoles::IntegrationMethod::Gear2,
+                                _ => return Err(value.error(format!("unknown integration method '{}'", value.text)))
+                            };
+                        }
+                        "reltol" => self.options.newton.reltol = value.value()?,
+                        "abstol" => self.options.newton.abstol = value.value()?,
+                        "vntol" => self.options.newton.vntol = value.value()?,
+                        "itl1" => {
+                            let iterations = value.value()?;
+                            if iterations < 1.0 || iterations.fract() != 0.0 {
+                                return Err(value.error(String::from("iteration limit must be a positive integer")));
+                            }
+                            self.options.newton.max_iterations = iterations as usize;
+                        }
+                        _ => return Err(option.error(format!("unsupported option '{}'", option.text)))
+                    }
+                    index += 2;
+                }
+            }
+            ".model" | ".print" | ".plot" | ".probe" | ".save" => {}
+            _ => return Err(command.error(format!("unsupported control card '{}'", command.text)))
+        }
+        Ok(())
+    }
+
+    fn parse_element(&mut self, card: &Card) -> Result<(), ParseError> {
+        let name_token = card.tokens[0];
+        let name = String::from(card.name());
+        if self.names.insert(name.to_lowercase(), name.clone()).is_some() {
+            return Err(name_token.error(format!("duplicate element name '{name}'")));
+        }
+
+        if name.starts_with(['q', 'Q']) {
+            return self.parse_transistor(card, name);
+        }
+        if name.starts_with(['m', 'M']) {
+            return self.parse_mosfet(card, name);
+        }
+        if name.starts_with(['s', 'S']) {
+            return self.parse_switch(card, name);
+        }
+        if name.starts_with(['t', 'T']) {
+            return self.parse_transmission_line(card, name);
+        }
+        if name.starts_with(['k', 'K']) {
+            let inductors = [card.get(1, "first inductor")?, card.get(2, "second inductor")?]
+                .map(|token| (String::from(token.text), token.line, token.column));
+            let coefficient = card.get(3, "coupling coefficient")?.value()?;
+            expect_end(card, 4)?;
+            self.couplings.push(Coupling { name, inductors, coefficient });
+            return Ok(());
+        }
+
+        let anode = card.get(1, "positive node")?;
+        let catode = card.get(2, "negative node")?;
+        let mut control = None;
+
+        let behaviour: Box<dyn bipoles::BipoleBehaviour> = match name.chars().next().map(|c| c.to_ascii_lowercase()) {
+            Some('r') => {
+                let resistance = card.get(3, "resistance")?.value()?;
+                expect_end(card, 4)?;
+                Box::new(bipoles::Resistor::new(resistance))
+            }
+            Some('c') => {
+                let capacitance = card.get(3, "capacitance")?.value()?;
+                let initial_voltage = parse_initial_condition(card, 4)?;
+                Box::new(bipoles::Capacitor::new(capacitance, initial_voltage))
+            }
+            Some('l') => {
+                let induttance = card.get(3, "inductance")?.value()?;
+                let initial_i = parse_initial_condition(card, 4)?;
+                let (anode_id, catode_id) = (self.node(anode), self.node(catode));
+                self.inductors.push(PendingInductor { name, anode_id, catode_id, induttance, initial_i });
+                return Ok(());
+            }
+            Some(letter @ ('e' | 'g')) => {
+                control = Some((card.get(3, "positive controlling node")?, card.get(4, "negative controlling node")?));
+                let gain = card.get(5, if letter == 'e' { "gain" } else { "transconductance" })?.value()?;
+                expect_end(card, 6)?;
+                if letter == 'e' {
+                    Box::new(bipoles::VoltageControlledVoltageSource::new(gain))
+                } else {
+                    Box::new(bipoles::VoltageControlledCurrentSource::new(gain))
+                }
+            }
+            Some(letter @ ('f' | 'h')) => {
+                let source = card.get(3, "controlling voltage source")?;
+                let gain = card.get(4, if letter == 'f' { "gain" } else { "transresistance" })?.value()?;
+                expect_end(card, 5)?;
+                let behaviour: Box<dyn bipoles::BipoleBehaviour> = if letter == 'f' {
+                    Box::new(bipoles::CurrentControlledCurrentSource::new(gain))
+                } else {
+                    Box::new(bipoles::CurrentControlledVoltageSource::new(gain))
+                };
+                let (anode_id, catode_id) = (self.node(anode), self.node(catode));
+                self.current_controls.push(CurrentControl { behaviour, anode_id, catode_id, name,
+                    source: String::from(source.text), line: source.line, column: source.column });
+                return Ok(());
+            }
+            Some('v') => parse_source(card, true)?,
+            Some('i') => parse_source(card, false)?,
+            Some('d') => {
+                let model = match card.tokens.get(3) {
+                    Some(token) => {
+                        expect_end(card, 4)?;
+                        match self.models.get(&token.lowercase()) {
+                            Some(Model::Diode(model)) => model,
+                            Some(_) => return Err(token.error(format!("'{}' is not a diode model", token.text))),
+                            None => return Err(token.error(format!("unknown diode model '{}'", token.text)))
+                        }
+                    }
+                    None => &DiodeModel { current_s: DEFAULT_SATURATION_CURRENT, emission_coefficient: 1.0 }
+                };
+                Box::new(bipoles::Diode::new(model.current_s,
+                    model.emission_coefficient * THERMAL_VOLTAGE, 1.08, 0.9))
+            }
+            _ => return Err(name_token.error(format!("unsupported element '{name}'")))
+        };
+
+        let anode_id = self.node(anode);
+        let catode_id = self.node(catode);
+        match control {
+            Some((control_anode, control_catode)) => {
+                let control_anode_id = self.node(control_anode);
+                let control_catode_id = self.node(control_catode);
+                self.circuit.add_controlled_bipole(behaviour, anode_id, catode_id, control_anode_id, control_catode_id, name);
+            }
+            None => self.circuit.add_bipole(behaviour, anode_id, catode_id, name)
+        }
+
+        Ok(())
+    }
+
+    /// `Qname collector base emitter model`
+    fn parse_transistor(&mut self, card: &Card, name: String) -> Result<(), ParseError> {
+        let collector = card.get(1, "collector node")?;
+        let base = card.get(2, "base node")?;
+        let emitter = card.get(3, "emitter node")?;
+        let model = card.get(4, "transistor model")?;
+        expect_end(card, 5)?;
+        let (polarity, parameters) = match self.models.get(&model.lowercase()) {
+            Some(Model::Bjt(polarity, parameters)) => (*polarity, *parameters),
+            Some(_) => return Err(model.error(format!("'{}' is not a transistor model", model.text))),
+            None => return Err(model.error(format!("unknown transistor model '{}'", model.text)))
+        };
+
+        let terminals = [self.node(collector), self.node(base), self.node(emitter)];
+        self.circuit.add_device(Box::new(transistors::Bjt::new(polarity, parameters)), &terminals, name);
+        Ok(())
+    }
+
+    /// `Mname drain gate source bulk model [W=width] [L=length]`
+    fn parse_mosfet(&mut self, card: &Card, name: String) -> Result<(), ParseError> {
+        let nodes = [card.get(1, "drain node")?, card.get(2, "gate node")?, card.get(3, "source node")?,
+            card.get(4, "bulk node")?];
+        let model = card.get(5, "transistor model")?;
+        let (channel, mut parameters) = match self.models.get(&model.lowercase()) {
+            Some(Model::Mosfet(channel, parameters)) => (*channel, *parameters),
+            Some(_) => return Err(model.error(format!("'{}' is not a MOSFET model", model.text))),
+            None => return Err(model.error(format!("unknown MOSFET model '{}'", model.text)))
+        };
+
+        let mut index = 6;
+        while index < card.tokens.len() {
+            let parameter = card.tokens[index];
+            let value = card.get(index + 1, &format!("value for parameter '{}'", parameter.text))?.value()?;
+            match parameter.lowercase().as_str() {
+                "w" => parameters.width = value,
+                "l" => parameters.length = value,
+                _ => return Err(parameter.error(format!("unexpected '{}'", parameter.text)))
+            }
+            index += 2;
+        }
+
+        let terminals = nodes.map(|node| self.node(node));
+        self.circuit.add_device(Box::new(transistors::Mosfet::with_bulk(channel, parameters)), &terminals, name);
+        Ok(())
+    }
+
+    /// `Sname n+ n- nc+ nc- model [ON|OFF]`, open unless ON is given.
+    fn parse_switch(&mut self, card: &Card, name: String) -> Result<(), ParseError> {
+        let nodes = [card.get(1, "positive node")?, card.get(2, "negative node")?,
+            card.get(3, "positive controlling node")?, card.get(4, "negative controlling node")?];
+        let model = card.get(5, "switch model")?;
+        let parameters = match self.models.get(&model.lowercase()) {
+            Some(Model::Switch(parameters)) => *parameters,
+            Some(_) => return Err(model.error(format!("'{}' is not a switch model", model.text))),
+            None => return Err(model.error(format!("unknown switch model '{}'", model.text)))
+        };
+        let closed = match card.tokens.get(6) {
+            Some(state) => match state.lowercase().as_str() {
+                "on" => true,
+                "off" => false,
+                _ => return Err(state.error(format!("expected ON or OFF, got '{}'", state.text)))
+            },
+            None => false
+        };
+        expect_end(card, 7)?;
+
+        let terminals = nodes.map(|node| self.node(node));
+        self.circuit.add_device(Box::new(switches::VoltageSwitch::new(parameters, closed)), &terminals, name);
+        Ok(())
+    }
+
+    /// `Tname p1 n1 p2 n2 Z0=impedance TD=delay`
+    fn parse_transmission_line(&mut self, card: &Card, name: String) -> Result<(), ParseError> {
+        let nodes = [card.get(1, "first port positive node")?, card.get(2, "first port negative node")?,
+            card.get(3, "second port positive node")?, card.get(4, "second port negative node")?];
+        let (mut impedance, mut delay_sec) = (None, None);
+        let mut index = 5;
+        while index < card.tokens.len() {
+            let parameter = card.tokens[index];
+            let value = card.get(index + 1, &format!("value for parameter '{}'", parameter.text))?.value()?;
+            match parameter.lowercase().as_str() {
+                "z0" => impedance = Some(value),
+                "td" => delay_sec = Some(value),
+                _ => return Err(parameter.error(format!("unexpected '{}'", parameter.text)))
+            }
+            index += 2;
+        }
+        let missing = |what: &str| ParseError::new(card.end_line, card.end_column, format!("missing {what}"));
+        let impedance = impedance.ok_or_else(|| missing("characteristic impedance Z0"))?;
+        let delay_sec = delay_sec.ok_or_else(|| missing("delay TD"))?;
+
+        let terminals = nodes.map(|node| self.node(node));
+        self.circuit.add_device(Box::new(transmission_lines::TransmissionLine::new(impedance, delay_sec)), &terminals, name);
+        Ok(())
+    }
+
+    fn add_current_controlled(&mut self) -> Result<(), ParseError> {
+        for control in self.current_controls.drain(..) {
+            let source = match self.names.get(&control.source.to_lowercase()) {
+                Some(source) if source.starts_with(['v', 'V']) => source.clone(),
+                Some(_) => return Err(ParseError::new(control.line, control.column,
+                    format!("'{}' is not a voltage source", control.source))),
+                None => return Err(ParseError::new(control.line, control.column,
+                    format!("unknown controlling voltage source '{}'", control.source)))
+            };
+            self.circuit.add_current_controlled_bipole(control.behaviour, control.anode_id, control.catode_id,
+                source, control.name);
+        }
+        Ok(())
+    }
+
+    fn add_inductors(&mut self) -> Result<(), ParseError> {
+        let mut inductors: HashMap<String, PendingInductor> = self.inductors.drain(..)
+            .map(|inductor| (inductor.name.to_lowercase(), inductor))
+            .collect();
+
+        for coupling in self.couplings.drain(..) {
+            let mut windings = Vec::new();
+            for (name, line, column) in &coupling.inductors {
+                match inductors.remove(&name.to_lowercase()) {
+                    Some(inductor) => windings.push(inductor),
+                    None => return Err(ParseError::new(*line, *column, match self.names.get(&name.to_lowercase()) {
+                        Some(element) if element.starts_with(['l', 'L']) => format!("'{name}' is already coupled"),
+                        Some(_) => format!("'{name}' is not an inductor"),
+                        None => format!("unknown inductor '{name}'")
+                    }))
+                }
+            }
+            let (primary, secondary) = (&windings[0], &windings[1]);
+            let device = magnetics::CoupledInductors::new([primary.induttance, secondary.induttance], coupling.coefficient,
+                [primary.initial_i, secondary.initial_i]);
+            let terminals = [primary.anode_id, primary.catode_id, secondary.anode_id, secondary.catode_id];
+            self.circuit.add_device(Box::new(device), &terminals, coupling.name);
+        }
+
+        for inductor in inductors.into_values() {
+            self.circuit.add_bipole(Box::new(bipoles::Inductor::new(inductor.induttance, inductor.initial_i)),
+                inductor.anode_id, inductor.catode_id, inductor.name);
+        }
+        Ok(())
+    }
+}
+
+fn expect_end(card: &Card, index: usize) -> Result<(), ParseError> {
+    match card.tokens.get(index) {
+        Some(token) => Err(token.error(format!("unexpected '{}'", token.text))),
+        None => Ok(())
+    }
+}
+
+fn parse_initial_condition(card: &Card, index: usize) -> Result<f64, ParseError> {
+    match card.tokens.get(index) {
+        Some(token) if token.text.eq_ignore_ascii_case("ic") => {
+            let value = card.get(index + 1, "initial condition")?.value()?;
+            expect_end(card, index + 2)?;
+            Ok(value)
+        }
+        Some(token) => Err(token.error(format!("unexpected '{}'", token.text))),
+        None => Ok(0.0)
+    }
+}
+
+fn parse_source(card: &Card, is_voltage: bool) -> Result<Box<dyn bipoles::BipoleBehaviour>, ParseError> {
+    let mut dc: Option<f64> = None;
+    let mut waveform: Option<waveforms::Waveform> = None;
+    let mut ac: Option<(f64, f64)> = None;
+    let mut index = 3;
+
+    while index < card.tokens.len() {
+        let token = card.tokens[index];
+        match token.lowercase().as_str() {
+            "dc" => {
+                dc = Some(card.get(index + 1, "DC value")?.value()?);
+                index += 2;
+            }
+            "sin" | "pulse" | "pwl" | "exp" | "sffm" => {
+                if waveform.is_some() {
+                    return Err(token.error(format!("unexpected '{}'", token.text)));
+                }
+                index += 1;
+                if token.lowercase() == "pwl" && card.tokens.get(index).is_some_and(|token| token.lowercase() == "file") {
+                    let path = card.get(index + 1, "PWL file")?;
+                    let text = std::fs::read_to_string(path.text)
+                        .map_err(|error| path.error(format!("cannot read '{}': {error}", path.text)))?;
+                    let points = waveforms::Waveform::pwl_from_csv(&text)
+                        .map_err(|message| path.error(format!("'{}': {message}", path.text)))?;
+                    waveform = Some(points);
+                    index += 2;
+                    continue;
+                }
+                let mut parameters = Vec::new();
+                while let Some(value) = card.tokens.get(index).and_then(|token| parse_value(token.text)) {
+                    parameters.push(value);
+                    index += 1;
+                }
+                waveform = Some(parse_waveform(token, &parameters)?);
+            }
+            "ac" => {
+                let magnitude = card.get(index + 1, "AC magnitude")?.value()?;
+                index += 2;
+                let mut phase_deg = 0.0;
+                if let Some(phase) = card.tokens.get(index).and_then(|token| parse_value(token.text)) {
+                    phase_deg = phase;
+                    index += 1;
+                }
+                ac = Some((magnitude, phase_deg));
+            }
+            _ => {
+                if dc.is_some() {
+                    return Err(token.error(format!("unexpected '{}'", token.text)));
+                }
+                dc = Some(token.value()?);
+                index += 1;
+            }
+        }
+    }
+
+    match waveform {
+        Some(waveform) => {
+            let (magnitude, phase_deg) = ac.unwrap_or((0.0, 0.0));
+            match (is_voltage, dc) {
+                (true, Some(value)) => Ok(Box::new(waveforms::WaveformVoltageSource::new_dc(waveform, value, magnitude, phase_deg))),
+                (true, None) => Ok(Box::new(waveforms::WaveformVoltageSource::new_ac(waveform, magnitude, phase_deg))),
+                (false, Some(value)) => Ok(Box::new(waveforms::WaveformCurrentSource::new_dc(waveform, value, magnitude, phase_deg))),
+                (false, None) => Ok(Box::new(waveforms::WaveformCurrentSource::new_ac(waveform, magnitude, phase_deg)))
+            }
+        }
+        None => {
+            let value = match (dc, ac) {
+                (Some(value), _) => value,
+                (None, Some(_)) => 0.0,
+                (None, None) => return Err(ParseError::new(card.end_line, card.end_column, String::from("missing source value")))
+            };
+            match (is_voltage, ac) {
+                (true, Some((magnitude, phase_deg))) => Ok(Box::new(bipoles::VoltageSource::new_ac(value, magnitude, phase_deg))),
+                (true, None) => Ok(Box::new(bipoles::VoltageSource::new(value))),
+                (false, Some((magnitude, phase_deg))) => Ok(Box::new(bipoles::CurrentSource::new_ac(value, magnitude, phase_deg))),
+                (false, None) => Ok(Box::new(bipoles::CurrentSource::new(value)))
+            }
+        }
+    }
+}
+
+/// Builds a waveform from the positional parameters of its SPICE function; parameters that
+/// SPICE derives from the time step default to sharp edges instead.
+fn parse_waveform(token: Token, parameters: &[f64]) -> Result<waveforms::Waveform, ParseError> {
+    let name = token.text.to_uppercase();
+    let (required, maximum, needs) = match name.as_str() {
+        "SIN" => (3, 6, "offset, amplitude and frequency"),
+        "PULSE" => (2, 7, "initial and pulsed values"),
+        "EXP" => (4, 6, "initial and pulsed values, rise delay and rise time constant"),
+        "SFFM" => (3, 5, "offset, amplitude and carrier frequency"),
+        _ => (2, usize::MAX, "time-value pairs")
+    };
+    if parameters.len() < required || (name == "PWL" && !parameters.len().is_multiple_of(2)) {
+        return Err(token.error(format!("{name} needs {needs}")));
+    }
+    if parameters.len() > maximum {
+        return Err(token.error(format!("{name} takes at most {maximum} parameters, got {}", parameters.len())));
+    }
+    let parameter = |index: usize, default: f64| parameters.get(index).copied().unwrap_or(default);
+
+    Ok(match name.as_str() {
+        "SIN" => waveforms::Waveform::Sin {
+            offset: parameters[0], amplitude: parameters[1], frequency_hz: parameters[2],
+            delay_sec: parameter(3, 0.0), damping: parameter(4, 0.0), phase_deg: parameter(5, 0.0)
+        },
+        "PULSE" => waveforms::Waveform::Pulse {
+            initial: parameters[0], pulsed: parameters[1], delay_sec: parameter(2, 0.0),
+            rise_sec: parameter(3, 0.0), fall_sec: parameter(4, 0.0),
+            width_sec: parameter(5, f64::INFINITY), period_sec: parameter(6, f64::INFINITY)
+        },
+        "EXP" => waveforms::Waveform::Exp {
+            initial: parameters[0], pulsed: parameters[1], rise_delay_sec: parameters[2], rise_tau_sec: parameters[3],
+            fall_delay_sec: parameter(4, f64::INFINITY), fall_tau_sec: parameter(5, parameters[3])
+        },
+        "SFFM" => waveforms::Waveform::Sffm {
+            offset: parameters[0], amplitude: parameters[1], carrier_hz: parameters[2],
+            modulation_index: parameter(3, 0.0), signal_hz: parameter(4, 0.0)
+        },
+        _ => waveforms::Waveform::Pwl(parameters.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values() {
+        assert_eq!(parse_value("10"), Some(10.0));
+        assert_eq!(parse_value("2.5k"), Some(2500.0));
+        assert_eq!(parse_value("1Meg"), Some(1e6));
+        assert_eq!(parse_value("-3e-3"), Some(-3e-3));
+        assert_eq!(parse_value("10uF"), Some(10.0 * 1e-6));
+        assert_eq!(parse_value("4.7nH"), Some(4.7 * 1e-9));
+        assert_eq!(parse_value("1p"), Some(1e-12));
+        assert_eq!(parse_value("5mA"), Some(5.0 * 1e-3));
+        assert_eq!(parse_value("abc"), None);
+        assert_eq!(parse_value("1k5"), None);
+    }
+
+    #[test]
+    fn test_divider() {
+        let netlist = parse("divider\n\
+            * a resistive divider\n\
+            V1 in 0 DC 10\n\
+            R1 in out 1k\n\
+            R2 out 0 1k ; bottom leg\n\
+            .tran 0.5 1\n\
+            .op\n\
+            .end\n").unwrap();
+
+        assert_eq!(netlist.title, "divider");
+        assert!(matches!(netlist.analyses[..], [Analysis::Transient { step_sec, stop_sec }, Analysis::OperatingPoint]
+            if step_sec == 0.5 && stop_sec == 1.0));
+
+        let mut circuit = netlist.circuit;
+        let out = circuit.simulate(1.0, 0.5).unwrap();
+        let id = netlist.nodes.get("out").unwrap();
+        let voltage = out.node_voltages.get(id).unwrap();
+        assert!((voltage[0] - 5.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_continuation_and_models() {
+        let netlist = parse("rectifier\n\
+            V1 1 0 SIN(0 10\n\
+            + 1)\n\
+            D1 1 2 dmod\n\
+            R1 2 gnd 10\n\
+            C1 2 0 20u IC=1\n\
+            .model dmod D(IS=1e-15 N=1)\n\
+            .end\n").unwrap();
+
+        assert_eq!(netlist.nodes.len(), 3);
+        assert_eq!(netlist.nodes.get("0"), Some(&0));
+    }
+
+    #[test]
+    fn test_options() {
+        let netlist = parse("tank\nC1 1 0 1u IC=1\nL1 1 0 1m\n.options method=gear\n").unwrap();
+        assert_eq!(netlist.options.method, bipoles::IntegrationMethod::Gear2);
+
+        let netlist = parse("diode\nD1 1 0\nR1 1 0 1k\n.options reltol=1e-4 vntol=1u itl1=50\n").unwrap();
+        assert_eq!(netlist.options.newton.reltol, 1e-4);
+        assert_eq!(netlist.options.newton.vntol, 1e-6);
+        assert_eq!(netlist.options.newton.max_iterations, 50);
+
+        let error = parse("tank\nC1 1 0 1u\n.options method=rk4\n").err().unwrap();
+        assert_eq!((error.line, error.column), (3, 17));
+    }
+
+    #[test]
+    fn test_ac() {
+        let netlist = parse("low pass\n\
+            V1 in 0 AC 1 90\n\
+            R1 in out 1k\n\
+            C1 out 0 1u\n\
+            .ac dec 10 1 1meg\n\
+            .end\n").unwrap();
+
+        assert!(matches!(netlist.analyses[..], [Analysis::Ac { sweep: bipoles::Sweep::Decade, points: 10, start_hz, stop_hz }]
+            if start_hz == 1.0 && stop_hz == 1e6));
+
+        let mut circuit = netlist.circuit;
+        let out = circuit.ac_sweep(bipoles::Sweep::Linear, 1, 1e-3, 1e-3).unwrap();
+        let voltage = out.node_voltages.get(netlist.nodes.get("out").unwrap()).unwrap()[0];
+        assert!(voltage.re.abs() < 1e-3 && (voltage.im - 1.0).abs() < 1e-3);
+
+        let error = parse("title\nV1 1 0 AC 1\n.ac log 10 1 10\n").err().unwrap();
+        assert_eq!((error.line, error.column), (3, 5));
+
+        // a Norton stimulus, one source with a waveform for the transient as well
+        let netlist = parse("current driven\n\
+            I1 0 out 1m AC 1m\n\
+            I2 0 out SIN(0 1m 1k) AC 2m -90\n\
+            R1 out 0 1k\n\
+            .end\n").unwrap();
+        let mut circuit = netlist.circuit;
+        let out = circuit.ac_sweep(bipoles::Sweep::Linear, 1, 1e3, 1e3).unwrap();
+        let voltage = out.node_voltages.get(netlist.nodes.get("out").unwrap()).unwrap()[0];
+        assert!((voltage.re - 1.0).abs() < 1e-9 && (voltage.im + 2.0).abs() < 1e-9);
+        assert!((circuit.operating_point().unwrap().voltage("out").unwrap() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_waveform_sources() {
+        let path = std::env::temp_dir().join(format!("netlist_pwl_{}.csv", std::process::id()));
+        std::fs::write(&path, "time,current\n0,1e-3\n2e-3,3e-3\n").unwrap();
+        let netlist = parse(&format!("sources\n\
+            V1 a 0 PULSE(0 5 1m 0 0 2m 4m) AC 1\n\
+            R1 a 0 1k\n\
+            V2 b 0 SIN(1 2 250 0 0 90)\n\
+            R2 b 0 1k\n\
+            V3 c 0 EXP(0 1 0 1m)\n\
+            R3 c 0 1k\n\
+            I1 0 d PWL FILE={}\n\
+            R4 d 0 1k\n\
+            I2 0 e SFFM(0 1m 1k)\n\
+            R5 e 0 1k\n\
+            .end\n", path.display())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut circuit = netlist.circuit;
+        let out = circuit.simulate(4e-3, 1e-3).unwrap();
+        let voltage = |node: &str| out.node_voltages.get(netlist.nodes.get(node).unwrap()).unwrap();
+        for sample in 0..4 {
+            let time = sample as f64 * 1e-3;
+            assert!((voltage("a")[sample] - [0.0, 5.0, 5.0, 0.0][sample]).abs() < 1e-9, "{sample}");
+            assert!((voltage("b")[sample] - (1.0 + 2.0 * (2.0 * std::f64::consts::PI * 250.0 * time).cos())).abs() < 1e-9);
+            assert!((voltage("c")[sample] - (1.0 - (-time/1e-3).exp())).abs() < 1e-9);
+            assert!((voltage("d")[sample] - (1.0 + time/1e-3).min(3.0)).abs() < 1e-9);
+            assert!((voltage("e")[sample]).abs() < 1e-9);
+        }
+
+        let error = parse("title\nV1 1 0 PWL(0 1 1m)\n").err().unwrap();
+        assert_eq!((error.line, error.column, error.message.as_str()), (2, 8, "PWL needs time-value pairs"));
+
+        // as in SPICE, a DC value beside a waveform biases the operating point and leaves the
+        // transient to the waveform
+        let netlist = parse("dc and waveform\n\
+            V1 a 0 DC 1 SIN(0 1 1k)\n\
+            R1 a 0 1k\n\
+            I1 0 b 2m PULSE(0 1m 1m)\n\
+            R2 b 0 1k\n\
+            .end\n").unwrap();
+        let mut circuit = netlist.circuit;
+        let op = circuit.operating_point().unwrap();
+        assert!((op.voltage("a").unwrap() - 1.0).abs() < 1e-9 && (op.voltage("b").unwrap() - 2.0).abs() < 1e-9);
+        let out = circuit.simulate(0.75e-3, 0.25e-3).unwrap();
+        assert!((out.voltage("a").unwrap()[1] - 1.0).abs() < 1e-9 && out.voltage("a").unwrap()[2].abs() < 1e-9);
+        assert!(out.voltage("b").unwrap()[1].abs() < 1e-9);
+
+        let error = parse("title\nI1 1 0 PWL FILE=/nonexistent/data.csv\n").err().unwrap();
+        assert_eq!((error.line, error.column), (2, 17));
+        assert!(error.message.starts_with("cannot read '/nonexistent/data.csv'"));
+    }
+
+    #[test]
+    fn test_coupled_inductors() {
+        let netlist = parse("forward converter magnetics\n\
+            V1 in 0 AC 1\n\
+            R1 in p 1\n\
+            K1 LP LS 0.99\n\
+            LP p 0 1m\n\
+            LS s 0 4m IC=0\n\
+            L3 s x 1u\n\
+            RL x 0 1g\n\
+            .end\n").unwrap();
+        let mut circuit = netlist.circuit;
+        let out = circuit.ac_sweep(bipoles::Sweep::Linear, 1, 1e3, 1e3).unwrap();
+        let voltage = |node: &str| out.node_voltages.get(netlist.nodes.get(node).unwrap()).unwrap()[0];
+        let ratio = voltage("s")/voltage("p");
+        assert!((ratio.re - 0.99 * 2.0).abs() < 1e-6 && ratio.im.abs() < 1e-6);
+        assert!(out.currents.contains_key("K1.p1") && out.currents.contains_key("L3") && !out.currents.contains_key("LP"));
+
+        let error = parse("title\nK1 L1 R1 0.5\nL1 1 0 1m\nR1 1 0 1\n").err().unwrap();
+        assert_eq!((error.line, error.column, error.message.as_str()), (2, 7, "'R1' is not an inductor"));
+
+        let error = parse("title\nL1 1 0 1m\nL2 1 0 1m\nK1 L1 L2 0.5\nK2 L2 L3 0.5\n").err().unwrap();
+        assert_eq!((error.line, error.column, error.message.as_str()), (5, 4, "'L2' is already coupled"));
+
+        let error = parse("title\nL1 1 0 1m\nK1 L1 L9 0.5\n").err().unwrap();
+        assert_eq!(error.message, "unknown inductor 'L9'");
+    }
+
+    #[test]
+    fn test_controlled_sources() {
+        let netlist = parse("gain stages\n\
+            V1 in 0 2\n\
+            R1 in 0 1k\n\
+            E1 mid 0 in 0 3\n\
+            G1 0 out mid 0 1m\n\
+            R2 out 0 2k\n\
+            .end\n").unwrap();
+
+        let mut circuit = netlist.circuit;
+        let op = circuit.operating_point().unwrap();
+        assert!((op.voltage("mid").unwrap() - 6.0).abs() < 1e-9);
+        assert!((op.voltage("out").unwrap() - 12.0).abs() < 1e-9);
+
+        let error = parse("title\nE1 1 0 2 0\n").err().unwrap();
+        assert_eq!(error.message, "missing gain");
+
+        // the sensing source may come later in the deck and is matched regardless of case
+        let netlist = parse("current stages\n\
+            F1 0 out vsense 2\n\
+            H1 tia 0 VSENSE 1k\n\
+            R2 out 0 1k\n\
+            R3 tia 0 1k\n\
+            V1 in 0 1\n\
+            R1 in sense 1k\n\
+            VSENSE sense 0 0\n\
+            .end\n").unwrap();
+
+        let mut circuit = netlist.circuit;
+        let op = circuit.operating_point().unwrap();
+        assert!((op.voltage("out").unwrap() - 2.0).abs() < 1e-9);
+        assert!((op.voltage("tia").unwrap() - 1.0).abs() < 1e-9);
+
+        let error = parse("title\nV1 1 0 1\nF1 0 1 VX 2\n").err().unwrap();
+        assert_eq!((error.line, error.column, error.message.as_str()), (3, 8, "unknown controlling voltage source 'VX'"));
+
+        let error = parse("title\nR1 1 0 1\nH1 2 0 R1 2\n").err().unwrap();
+        assert_eq!(error.message, "'R1' is not a voltage source");
+    }
+
+    #[test]
+    fn test_transistors() {
+        let netlist = parse("common emitter\n\
+            VCC vcc 0 10\n\
+            R1 vcc base 47k\n\
+            R2 base 0 10k\n\
+            RC vcc collector 4.7k\n\
+            RE emitter 0 1k\n\
+            Q1 collector base emitter qmod\n\
+            .model qmod NPN(IS=1e-16 BF=100 VAF=100)\n\
+            .end\n").unwrap();
+
+        let mut circuit = netlist.circuit;
+        let op = circuit.operating_point().unwrap();
+        let voltage_be = op.voltage("base").unwrap() - op.voltage("emitter").unwrap();
+        assert!(voltage_be > 0.6 && voltage_be < 0.8);
+        assert!((op.currents["Q1.c"]/op.currents["RC"] - 1.0).abs() < 1e-4);
+
+        let error = parse("title\nQ1 1 2 0 qmod\n.model qmod PNP(NF=1)\n").err().unwrap();
+        assert_eq!(error.message, "unsupported transistor parameter 'NF'");
+
+        let error = parse("title\nD1 1 0 qmod\n.model qmod NPN\n").err().unwrap();
+        assert_eq!(error.message, "'qmod' is not a diode model");
+
+        let netlist = parse("nmos switch\n\
+            VDD vdd 0 5\n\
+            VG gate 0 5\n\
+            RD vdd drain 10k\n\
+            M1 drain gate 0 0 nmod W=10u L=1u\n\
+            .model nmod NMOS(VTO=0.7 KP=100u LAMBDA=0.01)\n\
+            .end\n").unwrap();
+
+        // deep in the triode region the switch pulls the drain close to ground
+        let mut circuit = netlist.circuit;
+        let op = circuit.operating_point().unwrap();
+        let voltage_ds = op.voltage("drain").unwrap();
+        assert!(voltage_ds > 0.0 && voltage_ds < 0.2);
+        assert!((op.currents["M1.d"]/op.currents["RD"] - 1.0).abs() < 1e-4);
+
+        let error = parse("title\nM1 1 2 0 0 nmod W=1u X=2\n.model nmod NMOS\n").err().unwrap();
+        assert_eq!((error.line, error.column), (2, 22));
+        let error = parse("title\nM1 1 2 0 0 qmod\n.model qmod NPN\n").err().unwrap();
+        assert_eq!(error.message, "'qmod' is not a MOSFET model");
+    }
+
+    #[test]
+    fn test_switch() {
+        let netlist = parse("switched pull-down\n\
+            V1 in 0 1\n\
+            R1 in out 1k\n\
+            VC ctl 0 PWL(0 0 1m 5 2m 0)\n\
+            S1 out 0 ctl 0 smod\n\
+            S2 in 0 ctl 0 smod2 OFF\n\
+            .model smod SW(VT=2.5 VH=0.5 RON=1 ROFF=1g)\n\
+            .model smod2 SW(VT=10)\n\
+            .end\n").unwrap();
+
+        // closed from 3 V on the way up until 2 V on the way down
+        let mut circuit = netlist.circuit;
+        let out = circuit.simulate(2e-3, 0.1e-3).unwrap();
+        let voltage = out.voltage("out").unwrap();
+        for (sample, closed) in [(5, false), (7, true), (15, true), (17, false)] {
+            assert_eq!(voltage[sample] < 0.01, closed, "{sample}");
+        }
+        assert!(out.currents.contains_key("S2.p"));
+
+        let error = parse("title\nS1 1 0 2 0 smod ON X\n.model smod SW\n").err().unwrap();
+        assert_eq!((error.line, error.column), (2, 20));
+        let error = parse("title\nS1 1 0 2 0 smod MAYBE\n.model smod SW\n").err().unwrap();
+        assert_eq!(error.message, "expected ON or OFF, got 'MAYBE'");
+        let error = parse("title\nS1 1 0 2 0 dmod\n.model dmod D\n").err().unwrap();
+        assert_eq!(error.message, "'dmod' is not a switch model");
+        let error = parse("title\n.model smod SW(VON=1)\n").err().unwrap();
+        assert_eq!(error.message, "unsupported switch parameter 'VON'");
+    }
+
+    #[test]
+    fn test_transmission_line() {
+        let netlist = parse("open stub\n\
+            V1 in 0 PULSE(0 1 0 0.1u 0.1u 1 2)\n\
+            RS in a 50\n\
+            T1 a 0 b 0 Z0=50 TD=1u\n\
+            .end\n").unwrap();
+
+        // the edge doubles at the open end
+        let mut circuit = netlist.circuit;
+        let out = circuit.simulate(2e-6, 0.1e-6).unwrap();
+        assert!((out.voltage("a").unwrap()[5] - 0.5).abs() < 1e-9);
+        assert!(out.voltage("b").unwrap()[5].abs() < 1e-9);
+        assert!((out.voltage("b").unwrap()[15] - 1.0).abs() < 1e-9);
+        assert!(out.currents.contains_key("T1.p2"));
+
+        let error = parse("title\nT1 1 0 2 0 Z0=50\n").err().unwrap();
+        assert_eq!((error.line, error.column, error.message.as_str()), (2, 17, "missing delay TD"));
+        let error = parse("title\nT1 1 0 2 0 Z0=50 F=1meg\n").err().unwrap();
+        assert_eq!(error.message, "unexpected 'F'");
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = parse("title\nR1 1 0 1x2\n").err().unwrap();
+        assert_eq!((error.line, error.column), (2, 8));
+
+        let error = parse("title\nR1 1 0\n").err().unwrap();
+        assert_eq!((error.line, error.column), (2, 7));
+
+        let error = parse("title\nV1 1 0 5\nZ1 1 0 0\n").err().unwrap();
+        assert_eq!((error.line, error.column), (3, 1));
+
+        let error = parse("title\nQ1 1 2 0 dmod\n.model dmod D(IS=1e-15)\n").err().unwrap();
+        assert_eq!((error.line, error.column), (2, 10));
+        assert_eq!(error.message, "'dmod' is not a transistor model");
+
+        let error = parse("title\nD1 1 0 missing\n").err().unwrap();
+        assert_eq!((error.line, error.column), (2, 8));
+
+        let error = parse("title\n+ R1 1 0 1\n").err().unwrap();
+        assert_eq!((error.line, error.column), (2, 1));
+
+        let error = parse("title\nR1 1 2 1\n").err().unwrap();
+        assert_eq!(error.message, "netlist has no ground node '0'");
+
+        let error = parse("title\nR1 1 0 1\nr1 1 0 1\n").err().unwrap();
+        assert_eq!((error.line, error.column), (3, 1));
+    }
+}
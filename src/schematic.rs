@@ -0,0 +1,227 @@
+//! Versioned JSON format for schematics drawn in the editor.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::{fmt, fs, io};
+
+use serde::{Deserialize, Serialize};
+
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    AnodeUp,
+    AnodeDown,
+    AnodeRight,
+    AnodeLeft
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NodeData {
+    pub id: usize,
+    pub position: (f32, f32)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WireData {
+    pub id: usize,
+    pub node1_id: usize,
+    pub node2_id: usize
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BipoleData {
+    pub name: String,
+    pub kind: String,
+    pub center_position: (f32, f32),
+    pub size: (f32, f32),
+    pub rotation: Rotation,
+    pub anode_node_id: usize,
+    pub catode_node_id: usize,
+    /// Pins besides anode and catode, such as the controlling pins of controlled sources.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_node_ids: Vec<usize>,
+    pub parameters: BTreeMap<String, f64>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Schematic {
+    pub version: u32,
+    pub nodes: Vec<NodeData>,
+    pub wires: Vec<WireData>,
+    pub bipoles: Vec<BipoleData>,
+    pub ground_id: Option<usize>
+}
+
+#[derive(Debug)]
+pub enum SchematicError {
+    Io(io::Error),
+    Format(serde_json::Error),
+    UnsupportedVersion(u32),
+    UnknownKind { name: String, kind: String },
+    MissingNode { element: String, node_id: usize },
+    /// A component whose extra pins do not match those of its kind.
+    PinCount { name: String, kind: String, expected: usize, found: usize }
+}
+
+impl fmt::Display for SchematicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchematicError::Io(error) => write!(f, "could not access schematic file: {error}"),
+            SchematicError::Format(error) => write!(f, "malformed schematic file: {error}"),
+            SchematicError::UnsupportedVersion(version) =>
+                write!(f, "schematic format version {version} is newer than the supported version {FORMAT_VERSION}"),
+            SchematicError::UnknownKind { name, kind } => write!(f, "component {name} has unknown kind '{kind}'"),
+            SchematicError::MissingNode { element, node_id } => write!(f, "{element} references missing node {node_id}"),
+            SchematicError::PinCount { name, kind, expected, found } =>
+                write!(f, "component {name} of kind '{kind}' needs {expected} extra pins, got {found}")
+        }
+    }
+}
+
+impl Error for SchematicError {}
+
+impl From<io::Error> for SchematicError {
+    fn from(error: io::Error) -> Self {
+        SchematicError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for SchematicError {
+    fn from(error: serde_json::Error) -> Self {
+        SchematicError::Format(error)
+    }
+}
+
+impl Default for Schematic {
+    fn default() -> Self {
+        Schematic::new()
+    }
+}
+
+impl Schematic {
+    pub fn new() -> Schematic {
+        Schematic { version: FORMAT_VERSION, nodes: Vec::new(), wires: Vec::new(), bipoles: Vec::new(), ground_id: None }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Reads a schematic, rejecting files from newer versions and references to nodes that do not exist.
+    pub fn from_json(text: &str) -> Result<Schematic, SchematicError> {
+        let value: serde_json::Value = serde_json::from_str(text)?;
+        if let Some(version) = value.get("version").and_then(|version| version.as_u64()) {
+            if version > FORMAT_VERSION as u64 {
+                return Err(SchematicError::UnsupportedVersion(version as u32));
+            }
+        }
+
+        let schematic: Schematic = serde_json::from_value(value)?;
+        schematic.check_nodes()?;
+        Ok(schematic)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), SchematicError> {
+        fs::write(path, self.to_json())?;
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Schematic, SchematicError> {
+        Schematic::from_json(&fs::read_to_string(path)?)
+    }
+
+    fn check_nodes(&self) -> Result<(), SchematicError> {
+        let has_node = |id: usize| self.nodes.iter().any(|node| node.id == id);
+
+        for wire in &self.wires {
+            for node_id in [wire.node1_id, wire.node2_id] {
+                if !has_node(node_id) {
+                    return Err(SchematicError::MissingNode { element: format!("wire {}", wire.id), node_id });
+                }
+            }
+        }
+        for bipole in &self.bipoles {
+            for node_id in [bipole.anode_node_id, bipole.catode_node_id].into_iter().chain(bipole.extra_node_ids.iter().copied()) {
+                if !has_node(node_id) {
+                    return Err(SchematicError::MissingNode { element: format!("component {}", bipole.name), node_id });
+                }
+            }
+        }
+        if let Some(node_id) = self.ground_id {
+            if !has_node(node_id) {
+                return Err(SchematicError::MissingNode { element: String::from("ground"), node_id });
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn divider() -> Schematic {
+        let mut schematic = Schematic::new();
+        for (id, position) in [(1, (100.0, 160.0)), (2, (100.0, 240.0)), (3, (240.0, 100.0)), (4, (160.0, 100.0))] {
+            schematic.nodes.push(NodeData { id, position });
+        }
+        schematic.wires.push(WireData { id: 1, node1_id: 1, node2_id: 4 });
+        schematic.bipoles.push(BipoleData {
+            name: String::from("v1"),
+            kind: String::from("voltage source"),
+            center_position: (100.0, 200.0),
+            size: (80.0, 20.0),
+            rotation: Rotation::AnodeUp,
+            anode_node_id: 1,
+            catode_node_id: 2,
+            extra_node_ids: Vec::new(),
+            parameters: BTreeMap::from([(String::from("value"), 10.0)])
+        });
+        schematic.bipoles.push(BipoleData {
+            name: String::from("r2"),
+            kind: String::from("resistor"),
+            center_position: (200.0, 100.0),
+            size: (80.0, 20.0),
+            rotation: Rotation::AnodeRight,
+            anode_node_id: 3,
+            catode_node_id: 4,
+            extra_node_ids: Vec::new(),
+            parameters: BTreeMap::from([(String::from("resistance"), 4.7e3)])
+        });
+        schematic.ground_id = Some(2);
+        schematic
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let schematic = divider();
+        let loaded = Schematic::from_json(&schematic.to_json()).unwrap();
+        assert_eq!(loaded, schematic);
+    }
+
+    #[test]
+    fn test_missing_node() {
+        let mut schematic = divider();
+        schematic.wires.push(WireData { id: 2, node1_id: 3, node2_id: 9 });
+
+        match Schematic::from_json(&schematic.to_json()) {
+            Err(SchematicError::MissingNode { element, node_id }) => {
+                assert_eq!(element, "wire 2");
+                assert_eq!(node_id, 9);
+            }
+            _ => panic!("expected a missing node error")
+        }
+    }
+
+    #[test]
+    fn test_newer_version() {
+        let mut schematic = divider();
+        schematic.version = FORMAT_VERSION + 1;
+
+        assert!(matches!(Schematic::from_json(&schematic.to_json()),
+            Err(SchematicError::UnsupportedVersion(version)) if version == FORMAT_VERSION + 1));
+        assert!(matches!(Schematic::from_json("{\"version\": 1}"), Err(SchematicError::Format(_))));
+    }
+}
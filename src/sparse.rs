@@ -0,0 +1,472 @@
+//! Sparse matrices for the MNA system and a left-looking (Gilbert-Peierls) sparse LU
+//! factorisation with a minimum-degree column ordering and threshold partial pivoting.
+//! When a matrix with an unchanged sparsity pattern is factorised again, the ordering,
+//! pivot sequence and factor patterns of the previous factorisation are reused.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap};
+use std::ops::{Add, Div, Mul, Sub};
+
+use mathru::algebra::abstr::Complex;
+
+/// Diagonal pivots are kept as long as they are within this fraction of the largest candidate.
+const PIVOT_THRESHOLD: f64 = 1e-3;
+const NONE: usize = usize::MAX;
+
+pub trait Element: Copy + PartialEq + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + Div<Output = Self> {
+    fn zero() -> Self;
+    fn magnitude(self) -> f64;
+}
+
+impl Element for f64 {
+    fn zero() -> Self {
+        0.0
+    }
+
+    fn magnitude(self) -> f64 {
+        self.abs()
+    }
+}
+
+impl Element for Complex<f64> {
+    fn zero() -> Self {
+        Complex::new(0.0, 0.0)
+    }
+
+    fn magnitude(self) -> f64 {
+        self.re.hypot(self.im)
+    }
+}
+
+/// Square matrix stored as sorted rows; entries, once created, stay in the pattern
+/// when their value is cleared.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SparseMatrix<T> {
+    rows: Vec<Vec<(usize, T)>>
+}
+
+impl<T: Element> SparseMatrix<T> {
+    pub fn new(size: usize) -> SparseMatrix<T> {
+        SparseMatrix { rows: vec![Vec::new(); size] }
+    }
+
+    pub fn size(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn nonzeros(&self) -> usize {
+        self.rows.iter().map(|row| row.len()).sum()
+    }
+
+    pub fn get(&self, row: usize, column: usize) -> T {
+        let entries = &self.rows[row];
+        match entries.binary_search_by_key(&column, |(column, _)| *column) {
+            Ok(position) => entries[position].1,
+            Err(_) => T::zero()
+        }
+    }
+
+    pub fn add(&mut self, row: usize, column: usize, value: T) {
+        let entries = &mut self.rows[row];
+        match entries.binary_search_by_key(&column, |(column, _)| *column) {
+            Ok(position) => entries[position].1 = entries[position].1 + value,
+            Err(position) => entries.insert(position, (column, value))
+        }
+    }
+
+    /// Zeroes a row, keeping its pattern.
+    pub fn clear_row(&mut self, row: usize) {
+        for entry in &mut self.rows[row] {
+            entry.1 = T::zero();
+        }
+    }
+
+    /// Zeroes every value, keeping the pattern.
+    pub fn clear(&mut self) {
+        for row in 0..self.rows.len() {
+            self.clear_row(row);
+        }
+    }
+
+    pub fn multiply(&self, vector: &[T]) -> Vec<T> {
+        self.rows.iter()
+            .map(|row| row.iter().fold(T::zero(), |sum, (column, value)| sum + *value * vector[*column]))
+            .collect()
+    }
+
+    fn columns(&self) -> Vec<Vec<(usize, T)>> {
+        let mut columns = vec![Vec::new(); self.rows.len()];
+        for (row, entries) in self.rows.iter().enumerate() {
+            for (column, value) in entries {
+                columns[*column].push((row, *value));
+            }
+        }
+        columns
+    }
+}
+
+/// Column ordering by minimum degree on the pattern of `A + A^T`, which keeps the
+/// fill-in of the factors low for the nearly symmetric MNA matrices.
+fn minimum_degree_order(columns: &[Vec<usize>]) -> Vec<usize> {
+    let size = columns.len();
+    let mut adjacency: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); size];
+    for (column, rows) in columns.iter().enumerate() {
+        for row in rows {
+            if *row != column {
+                adjacency[*row].insert(column);
+                adjacency[column].insert(*row);
+            }
+        }
+    }
+
+    let mut eliminated = vec![false; size];
+    let mut heap: BinaryHeap<Reverse<(usize, usize)>> = (0..size)
+        .map(|node| Reverse((adjacency[node].len(), node)))
+        .collect();
+    let mut order = Vec::with_capacity(size);
+
+    while let Some(Reverse((degree, node))) = heap.pop() {
+        if eliminated[node] || degree != adjacency[node].len() {
+            continue;
+        }
+        eliminated[node] = true;
+        order.push(node);
+
+        // the neighbours of an eliminated node become a clique
+        let neighbours: Vec<usize> = std::mem::take(&mut adjacency[node]).into_iter().collect();
+        for first in &neighbours {
+            adjacency[*first].remove(&node);
+            for second in &neighbours {
+                if first != second {
+                    adjacency[*first].insert(*second);
+                }
+            }
+        }
+        for neighbour in neighbours {
+            heap.push(Reverse((adjacency[neighbour].len(), neighbour)));
+        }
+    }
+    order
+}
+
+/// LU factorisation `P A Q = L U` of a `SparseMatrix`.
+#[derive(Clone, Debug, Default)]
+pub struct SparseLu<T> {
+    pattern: Vec<Vec<usize>>,
+    column_order: Vec<usize>,
+    pivot_row: Vec<usize>,
+    row_step: Vec<usize>,
+    /// Below-diagonal entries of each column of `L` as (original row, value); the diagonal is one.
+    lower: Vec<Vec<(usize, T)>>,
+    /// Above-diagonal entries of each column of `U` as (step, value), in a valid elimination order.
+    upper: Vec<Vec<(usize, T)>>,
+    pivots: Vec<T>,
+    symbolic_factorisations: usize
+}
+
+impl<T: Element> SparseLu<T> {
+    pub fn new() -> SparseLu<T> {
+        SparseLu {
+            pattern: Vec::new(),
+            column_order: Vec::new(),
+            pivot_row: Vec::new(),
+            row_step: Vec::new(),
+            lower: Vec::new(),
+            upper: Vec::new(),
+            pivots: Vec::new(),
+            symbolic_factorisations: 0
+        }
+    }
+
+    /// Number of factorisations that had to choose a new ordering and pivot sequence.
+    pub fn symbolic_factorisations(&self) -> usize {
+        self.symbolic_factorisations
+    }
+
+    /// Factorises the matrix; on failure returns the column (unknown) for which no pivot was found.
+    pub fn factor(&mut self, matrix: &SparseMatrix<T>) -> Result<(), usize> {
+        let columns = matrix.columns();
+        let pattern: Vec<Vec<usize>> = columns.iter()
+            .map(|column| column.iter().map(|(row, _)| *row).collect())
+            .collect();
+
+        if pattern == self.pattern && self.refactor(&columns) {
+            return Ok(());
+        }
+        if pattern != self.pattern {
+            self.column_order = minimum_degree_order(&pattern);
+            self.pattern = pattern;
+        }
+        self.symbolic_factorisations += 1;
+        let result = self.factor_with_pivoting(&columns);
+        if result.is_err() {
+            // a later call must not refactor along an incomplete pivot sequence
+            self.pattern.clear();
+        }
+        result
+    }
+
+    fn factor_with_pivoting(&mut self, columns: &[Vec<(usize, T)>]) -> Result<(), usize> {
+        let size = columns.len();
+        self.pivot_row = vec![NONE; size];
+        self.row_step = vec![NONE; size];
+        self.lower = vec![Vec::new(); size];
+        self.upper = vec![Vec::new(); size];
+        self.pivots = vec![T::zero(); size];
+
+        let mut work = vec![T::zero(); size];
+        let mut touched = vec![false; size];
+        let mut visited = vec![NONE; size];
+
+        for step in 0..size {
+            let column = self.column_order[step];
+            let mut rows: Vec<usize> = Vec::new();
+            let mut scale: f64 = 0.0;
+            for (row, value) in &columns[column] {
+                work[*row] = *value;
+                touched[*row] = true;
+                rows.push(*row);
+                scale = scale.max(value.magnitude());
+            }
+
+            let order = self.reach(&columns[column], step, &mut visited);
+            for earlier in &order {
+                let value = work[self.pivot_row[*earlier]];
+                for (row, factor) in &self.lower[*earlier] {
+                    if !touched[*row] {
+                        touched[*row] = true;
+                        rows.push(*row);
+                    }
+                    work[*row] = work[*row] - *factor * value;
+                }
+            }
+            self.upper[step] = order.iter().map(|earlier| (*earlier, work[self.pivot_row[*earlier]])).collect();
+
+            let candidates: Vec<usize> = rows.iter().copied().filter(|row| self.row_step[*row] == NONE).collect();
+            let largest = candidates.iter().fold(0.0, |largest: f64, row| largest.max(work[*row].magnitude()));
+            let pivot_row = if touched[column] && self.row_step[column] == NONE
+                && work[column].magnitude() >= PIVOT_THRESHOLD * largest {
+                Some(column)
+            } else {
+                candidates.iter().copied().max_by(|first, second| work[*first].magnitude().total_cmp(&work[*second].magnitude()))
+            };
+
+            let pivot_row = match pivot_row {
+                Some(row) if largest > size as f64 * f64::EPSILON * scale => row,
+                _ => return Err(column)
+            };
+            let pivot = work[pivot_row];
+            self.pivot_row[step] = pivot_row;
+            self.row_step[pivot_row] = step;
+            self.pivots[step] = pivot;
+            self.lower[step] = candidates.iter()
+                .filter(|row| **row != pivot_row)
+                .map(|row| (*row, work[*row]/pivot))
+                .collect();
+
+            for row in rows {
+                work[row] = T::zero();
+                touched[row] = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Earlier steps whose `L` columns update the given column, in elimination order.
+    fn reach(&self, column: &[(usize, T)], step: usize, visited: &mut [usize]) -> Vec<usize> {
+        let mut postorder = Vec::new();
+        let mut stack: Vec<(usize, usize)> = Vec::new();
+        for (row, _) in column {
+            let start = self.row_step[*row];
+            if start == NONE || visited[start] == step {
+                continue;
+            }
+            visited[start] = step;
+            stack.push((start, 0));
+            while let Some((node, position)) = stack.pop() {
+                let next = self.lower[node][position..].iter()
+                    .enumerate()
+                    .map(|(offset, (row, _))| (position + offset, self.row_step[*row]))
+                    .find(|(_, child)| *child != NONE && visited[*child] != step);
+                match next {
+                    Some((offset, child)) => {
+                        stack.push((node, offset + 1));
+                        visited[child] = step;
+                        stack.push((child, 0));
+                    }
+                    None => postorder.push(node)
+                }
+            }
+        }
+        postorder.reverse();
+        postorder
+    }
+
+    /// Numeric factorisation along the previous pivot sequence; fails when a pivot became too small.
+    fn refactor(&mut self, columns: &[Vec<(usize, T)>]) -> bool {
+        let size = columns.len();
+        let mut work = vec![T::zero(); size];
+
+        for step in 0..size {
+            for (row, value) in &columns[self.column_order[step]] {
+                work[*row] = *value;
+            }
+            for position in 0..self.upper[step].len() {
+                let earlier = self.upper[step][position].0;
+                let value = work[self.pivot_row[earlier]];
+                self.upper[step][position].1 = value;
+                for (row, factor) in &self.lower[earlier] {
+                    work[*row] = work[*row] - *factor * value;
+                }
+            }
+
+            let pivot = work[self.pivot_row[step]];
+            let largest = self.lower[step].iter().fold(pivot.magnitude(), |largest, (row, _)| largest.max(work[*row].magnitude()));
+            if pivot.magnitude() == 0.0 || pivot.magnitude() < PIVOT_THRESHOLD * largest {
+                return false;
+            }
+            self.pivots[step] = pivot;
+            for entry in &mut self.lower[step] {
+                entry.1 = work[entry.0]/pivot;
+                work[entry.0] = T::zero();
+            }
+            work[self.pivot_row[step]] = T::zero();
+            for (earlier, _) in &self.upper[step] {
+                work[self.pivot_row[*earlier]] = T::zero();
+            }
+        }
+        true
+    }
+
+    /// Solves `A x = rhs` with the last successful factorisation.
+    pub fn solve(&self, rhs: &[T]) -> Vec<T> {
+        let size = rhs.len();
+        let mut work = rhs.to_vec();
+        let mut solution = vec![T::zero(); size];
+
+        for step in 0..size {
+            let value = work[self.pivot_row[step]];
+            solution[step] = value;
+            for (row, factor) in &self.lower[step] {
+                work[*row] = work[*row] - *factor * value;
+            }
+        }
+        for step in (0..size).rev() {
+            let value = solution[step]/self.pivots[step];
+            solution[step] = value;
+            for (earlier, factor) in &self.upper[step] {
+                solution[*earlier] = solution[*earlier] - *factor * value;
+            }
+        }
+
+        let mut unknowns = vec![T::zero(); size];
+        for (step, column) in self.column_order.iter().enumerate() {
+            unknowns[*column] = solution[step];
+        }
+        unknowns
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_solves(matrix: &SparseMatrix<f64>, lu: &SparseLu<f64>, rhs: &[f64]) {
+        let solution = lu.solve(rhs);
+        for (value, expected) in matrix.multiply(&solution).iter().zip(rhs) {
+            assert!((value - expected).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_zero_diagonal() {
+        // a voltage source between node 1 and ground, a resistor between nodes 1 and 2
+        let mut matrix = SparseMatrix::new(4);
+        for (row, column, value) in [(0, 0, 1.0), (1, 1, 1.0), (1, 2, -1.0), (2, 1, -1.0), (2, 2, 1.0),
+            (1, 3, 1.0), (3, 1, 1.0), (2, 2, 1e-3)] {
+            matrix.add(row, column, value);
+        }
+        assert_eq!(matrix.get(3, 3), 0.0);
+
+        let mut lu = SparseLu::new();
+        lu.factor(&matrix).unwrap();
+        let solution = lu.solve(&[0.0, 0.0, 0.0, 5.0]);
+        assert!((solution[1] - 5.0).abs() < 1e-12);
+        assert_solves(&matrix, &lu, &[1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn test_pattern_reuse() {
+        let size = 50;
+        let mut matrix = SparseMatrix::new(size);
+        let mut lu = SparseLu::new();
+        let rhs: Vec<f64> = (0..size).map(|i| i as f64).collect();
+
+        for scale in [1.0, 2.0, 1e3] {
+            matrix.clear();
+            for i in 0..size {
+                matrix.add(i, i, 4.0 * scale);
+                if i + 1 < size {
+                    matrix.add(i, i + 1, -scale);
+                    matrix.add(i + 1, i, -1.0);
+                }
+            }
+            lu.factor(&matrix).unwrap();
+            assert_solves(&matrix, &lu, &rhs);
+        }
+        assert_eq!(lu.symbolic_factorisations(), 1);
+
+        // a new entry changes the pattern
+        matrix.add(0, size - 1, 1.0);
+        lu.factor(&matrix).unwrap();
+        assert_solves(&matrix, &lu, &rhs);
+        assert_eq!(lu.symbolic_factorisations(), 2);
+
+        // a pivot that vanishes forces a new pivot sequence
+        matrix.clear();
+        for i in 0..size {
+            matrix.add(i, i, if i == 0 { 0.0 } else { 4.0 });
+            if i + 1 < size {
+                matrix.add(i, i + 1, -1.0);
+                matrix.add(i + 1, i, -1.0);
+            }
+        }
+        lu.factor(&matrix).unwrap();
+        assert_solves(&matrix, &lu, &rhs);
+        assert_eq!(lu.symbolic_factorisations(), 3);
+    }
+
+    #[test]
+    fn test_singular() {
+        let mut matrix = SparseMatrix::new(3);
+        matrix.add(0, 0, 1.0);
+        matrix.add(1, 1, 1.0);
+        matrix.add(1, 2, 0.0);
+        matrix.add(2, 1, 0.0);
+        assert_eq!(SparseLu::new().factor(&matrix), Err(2));
+
+        let mut matrix = SparseMatrix::new(2);
+        for (row, column) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+            matrix.add(row, column, 1.0);
+        }
+        assert!(SparseLu::new().factor(&matrix).is_err());
+    }
+
+    #[test]
+    fn test_complex() {
+        let mut matrix = SparseMatrix::new(2);
+        matrix.add(0, 0, Complex::new(1.0, 1.0));
+        matrix.add(0, 1, Complex::new(0.0, -1.0));
+        matrix.add(1, 0, Complex::new(0.0, -1.0));
+        matrix.add(1, 1, Complex::new(2.0, 0.0));
+
+        let mut lu = SparseLu::new();
+        lu.factor(&matrix).unwrap();
+        let rhs = [Complex::new(1.0, 0.0), Complex::new(0.0, 1.0)];
+        let solution = lu.solve(&rhs);
+        for (value, expected) in matrix.multiply(&solution).iter().zip(rhs) {
+            assert!((*value - expected).magnitude() < 1e-12);
+        }
+    }
+}
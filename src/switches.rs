@@ -0,0 +1,371 @@
+//! Switches: a bipole opening and closing at given times, and a switch controlled by the
+//! voltage across two further terminals with a threshold and hysteresis (SPICE `S`).
+
+use mathru::algebra::abstr::Complex;
+
+use crate::bipoles::{check_finite, check_positive, BipoleBehaviour, IntegrationMethod, Model, NewtonOptions,
+    TimestepControl};
+use crate::devices::{Device, Solution, Stamper, Step};
+use crate::topology::BranchKind;
+
+fn check_resistances(on_resistance: f64, off_resistance: f64) -> Result<(), String> {
+    check_positive("on resistance", on_resistance)?;
+    check_positive("off resistance", off_resistance)
+}
+
+/// Switch toggling at each of `switching_times`; steps end on them.
+#[derive(Clone)]
+pub struct TimeSwitch {
+    on_resistance: f64,
+    off_resistance: f64,
+    initially_closed: bool,
+    switching_times: Vec<f64>
+}
+
+impl TimeSwitch {
+    pub fn new(on_resistance: f64, off_resistance: f64, initially_closed: bool, switching_times: Vec<f64>) -> TimeSwitch {
+        TimeSwitch { on_resistance, off_resistance, initially_closed, switching_times }
+    }
+
+    /// State during a step ending at `time`: toggled once at every switching time before it.
+    pub fn is_closed(&self, time: f64) -> bool {
+        let toggles = self.switching_times.partition_point(|switching_time| *switching_time < time);
+        self.initially_closed != (toggles % 2 == 1)
+    }
+}
+
+impl BipoleBehaviour for TimeSwitch {
+    fn linear_companion(&self, _timestep_sec: f64, current_time_sec: f64, _method: IntegrationMethod) -> Model {
+        let resistance = if self.is_closed(current_time_sec) { self.on_resistance } else { self.off_resistance };
+        Model::ConduttanceCurrentSource { conduttance: 1.0/resistance, current: 0.0 }
+    }
+
+    fn next_breakpoint(&self, time: f64) -> Option<f64> {
+        self.switching_times.iter().copied().find(|switching_time| *switching_time > time)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        check_resistances(self.on_resistance, self.off_resistance)?;
+        for switching_time in &self.switching_times {
+            check_finite("switching time", *switching_time)?;
+        }
+        match self.switching_times.windows(2).find(|pair| pair[1] <= pair[0]) {
+            Some(pair) => Err(format!("switching times must increase, got {} after {}", pair[1], pair[0])),
+            None => Ok(())
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SwitchParameters {
+    pub threshold: f64,
+    /// Half the width of the hysteresis band: the switch closes above `threshold + hysteresis`
+    /// and opens below `threshold - hysteresis`.
+    pub hysteresis: f64,
+    pub on_resistance: f64,
+    pub off_resistance: f64
+}
+
+impl Default for SwitchParameters {
+    fn default() -> Self {
+        SwitchParameters { threshold: 0.0, hysteresis: 0.0, on_resistance: 1.0, off_resistance: 1e12 }
+    }
+}
+
+/// Switch between `p` and `n` driven by v(cp) - v(cn). Its state changes at most once per
+/// Newton solve, once the iterates in the accepted state have settled beyond the threshold:
+/// an iterate overshooting on its way to convergence does not switch, and a switch that
+/// disturbs its own control cannot chatter within a step. With timestep control a step that
+/// switches is shortened until it ends close to the crossing of the threshold.
+#[derive(Clone)]
+pub struct VoltageSwitch {
+    parameters: SwitchParameters,
+    /// State at the last accepted step.
+    closed: bool,
+    /// State of the last Newton iterate.
+    closed_iterate: bool,
+    /// Criteria of the running Newton solve, by which an iterate has settled.
+    newton: NewtonOptions,
+    /// Control voltage of the previous Newton iterate, or of the initial guess.
+    iterate_voltage: Option<f64>,
+    /// Settled control voltage that left the accepted state during this solve, if any.
+    crossing_voltage: Option<f64>,
+    /// Control voltage at the last accepted step.
+    control_voltage: Option<f64>
+}
+
+impl VoltageSwitch {
+    pub fn new(parameters: SwitchParameters, closed: bool) -> VoltageSwitch {
+        VoltageSwitch { parameters, closed, closed_iterate: closed, newton: NewtonOptions::default(),
+            iterate_voltage: None, crossing_voltage: None, control_voltage: None }
+    }
+
+    fn conductance(&self) -> f64 {
+        1.0/if self.closed_iterate { self.parameters.on_resistance } else { self.parameters.off_resistance }
+    }
+
+    /// Threshold the control voltage crosses to leave the accepted state.
+    fn switching_threshold(&self) -> f64 {
+        let p = &self.parameters;
+        if self.closed { p.threshold - p.hysteresis } else { p.threshold + p.hysteresis }
+    }
+
+    fn control(solution: &Solution<f64>) -> f64 {
+        solution.voltage(2) - solution.voltage(3)
+    }
+}
+
+impl Device for VoltageSwitch {
+    fn terminals(&self) -> &'static [&'static str] {
+        &["p", "n", "cp", "cn"]
+    }
+
+    fn stamp(&self, _step: Step, stamper: &mut Stamper<f64>) {
+        stamper.add_conductance(stamper.node(0), stamper.node(1), self.conductance());
+    }
+
+    fn stamp_ac(&self, _omega: f64, stamper: &mut Stamper<Complex<f64>>) {
+        stamper.add_conductance(stamper.node(0), stamper.node(1), Complex::new(self.conductance(), 0.0));
+    }
+
+    fn currents(&self, _step: Step, solution: &Solution<f64>) -> Vec<f64> {
+        let current = self.conductance() * (solution.voltage(0) - solution.voltage(1));
+        vec![current, -current, 0.0, 0.0]
+    }
+
+    fn ac_currents(&self, _omega: f64, solution: &Solution<Complex<f64>>) -> Vec<Complex<f64>> {
+        let zero = Complex::new(0.0, 0.0);
+        let current = (solution.voltage(0) - solution.voltage(1)) * Complex::new(self.conductance(), 0.0);
+        vec![current, zero - current, zero, zero]
+    }
+
+    fn topology(&self, _step: Step) -> Vec<(usize, usize, BranchKind)> {
+        vec![(0, 1, BranchKind::Conductive)]
+    }
+
+    // the accepted state is memory, as the hysteresis depends on it
+    fn is_dynamic(&self) -> bool {
+        true
+    }
+
+    fn is_nonlinear(&self) -> bool {
+        true
+    }
+
+    fn update_operating_point(&mut self, solution: &Solution<f64>) {
+        if self.crossing_voltage.is_some() {
+            return;
+        }
+        let voltage = Self::control(solution);
+        let previous = self.iterate_voltage.replace(voltage);
+        let crossed = if self.closed {
+            voltage < self.switching_threshold()
+        } else {
+            voltage > self.switching_threshold()
+        };
+        let newton = &self.newton;
+        let settled = previous.is_some_and(|previous| {
+            (voltage - previous).abs() <= newton.reltol * voltage.abs().max(previous.abs()) + newton.vntol
+        });
+        // an iterate still moving may overshoot the threshold on its way to a solution that
+        // does not cross it, so the state only changes once Newton would accept the iterate
+        if crossed && settled {
+            self.closed_iterate = !self.closed;
+            self.crossing_voltage = Some(voltage);
+        }
+    }
+
+    fn reset_operating_point(&mut self, newton: &NewtonOptions) {
+        self.newton = *newton;
+        self.closed_iterate = self.closed;
+        self.iterate_voltage = None;
+        self.crossing_voltage = None;
+    }
+
+    fn warm_start(&mut self, solution: &Solution<f64>, newton: &NewtonOptions) {
+        self.reset_operating_point(newton);
+        self.iterate_voltage = Some(Self::control(solution));
+    }
+
+    fn update_state(&mut self, solution: &Solution<f64>, _timestep_sec: f64, _method: IntegrationMethod) {
+        self.closed = self.closed_iterate;
+        self.crossing_voltage = None;
+        self.control_voltage = Some(Self::control(solution));
+    }
+
+    /// Time by which a switching step overshoots the threshold crossing, interpolated from
+    /// the settled control voltage, relative to the smallest step.
+    fn truncation_error_ratio(&self, _solution: &Solution<f64>, timestep_sec: f64,
+        _method: IntegrationMethod, control: &TimestepControl) -> f64 {
+
+        let (previous_voltage, voltage) = match (self.control_voltage, self.crossing_voltage) {
+            (Some(previous_voltage), Some(voltage)) => (previous_voltage, voltage),
+            _ => return 0.0
+        };
+        // a control already past the threshold when the step started, even if it did not
+        // move over the step, crossed it before this step and no shorter step ends closer
+        let span = voltage - previous_voltage;
+        let fraction = (self.switching_threshold() - previous_voltage)/span;
+        if span == 0.0 || fraction < 0.0 {
+            return 0.0;
+        }
+        (1.0 - fraction.min(1.0)) * timestep_sec/control.min_timestep_sec
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let p = &self.parameters;
+        check_finite("threshold", p.threshold)?;
+        check_finite("hysteresis", p.hysteresis)?;
+        if p.hysteresis < 0.0 {
+            return Err(format!("hysteresis must not be negative, got {}", p.hysteresis));
+        }
+        check_resistances(p.on_resistance, p.off_resistance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bipoles::{Capacitor, Circuit, Diode, Resistor, SimulationError, SimulationOptions, VoltageSource};
+    use crate::waveforms::{Waveform, WaveformVoltageSource};
+
+    #[test]
+    fn test_time_switch() {
+        // 1 V charges the capacitor through 1k once the switch closes at 0.25 ms
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(VoltageSource::new(1.0)), "in", "0", String::from("V1"));
+        circ.add_bipole_between(Box::new(TimeSwitch::new(1e-3, 1e12, false, vec![0.25e-3, 0.75e-3])), "in", "a",
+            String::from("S1"));
+        circ.add_bipole_between(Box::new(Resistor::new(1e3 - 1e-3)), "a", "out", String::from("R1"));
+        circ.add_bipole_between(Box::new(Capacitor::new(1e-6, 0.0)), "out", "0", String::from("C1"));
+        let out = circ.simulate(1e-3, 1e-4).unwrap();
+        let voltage = out.voltage("out").unwrap();
+
+        // backward Euler from 0.25 ms over a step of 0.05 ms, then of 0.1 ms, and a last
+        // 0.05 ms before the switch opens again at 0.75 ms and holds the charge
+        let mut expected = 0.0;
+        for (sample, timestep) in [(3, 0.05e-3), (4, 0.1e-3), (5, 0.1e-3), (6, 0.1e-3), (7, 0.1e-3)] {
+            expected = (expected + timestep/1e-3)/(1.0 + timestep/1e-3);
+            assert!((voltage[sample] - expected).abs() < 1e-6, "{sample}");
+        }
+        assert!(voltage[2].abs() < 1e-6);
+        expected = (expected + 0.05)/1.05;
+        assert!((voltage[8] - expected).abs() < 1e-6);
+        assert!((voltage[9] - expected).abs() < 1e-6);
+        assert!(TimeSwitch::new(1.0, 1e6, true, vec![1e-3]).is_closed(1e-3));
+        assert!(!TimeSwitch::new(1.0, 1e6, true, vec![1e-3]).is_closed(1.1e-3));
+
+        circ.add_bipole_between(Box::new(TimeSwitch::new(1.0, 1e6, false, vec![2e-3, 1e-3])), "out", "0",
+            String::from("S2"));
+        assert!(matches!(circ.simulate(1e-3, 1e-4),
+            Err(SimulationError::InvalidParameter { element, message })
+                if element == "S2" && message == "switching times must increase, got 0.001 after 0.002"));
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        // the control rises to 5 V over 1 ms and falls back; the switch pulls the output to
+        // ground above 3 V and releases it below 2 V
+        let parameters = SwitchParameters { threshold: 2.5, hysteresis: 0.5, on_resistance: 1.0, off_resistance: 1e9 };
+        let mut circ = Circuit::new(0);
+        let triangle = Waveform::Pwl(vec![(0.0, 0.0), (1e-3, 5.0), (2e-3, 0.0)]);
+        circ.add_bipole_between(Box::new(WaveformVoltageSource::new(triangle)), "ctl", "0", String::from("V1"));
+        circ.add_bipole_between(Box::new(VoltageSource::new(1.0)), "in", "0", String::from("V2"));
+        circ.add_bipole_between(Box::new(Resistor::new(1e3)), "in", "out", String::from("R1"));
+        circ.add_device_between(Box::new(VoltageSwitch::new(parameters, false)), &["out", "0", "ctl", "0"],
+            String::from("S1"));
+        let out = circ.simulate(2e-3, 0.1e-3).unwrap();
+        let voltage = out.voltage("out").unwrap();
+        // 2.5 V and 3.5 V on the way up, 2.5 V and 1.5 V on the way down
+        for (sample, closed) in [(5, false), (7, true), (15, true), (17, false)] {
+            let expected = if closed { 1.0/1001.0 } else { 1e9/(1e9 + 1e3) };
+            assert!((voltage[sample] - expected).abs() < 1e-9, "{sample}");
+            assert!((out.currents["S1.p"][sample] - (1.0 - expected)/1e3).abs() < 1e-12, "{sample}");
+        }
+    }
+
+    #[test]
+    fn test_newton_tolerances() {
+        // the control of the hysteresis test moves by 0.5 V a step, within a vntol of 1 V, so
+        // Newton accepts the first iterate of each step and the switch must settle with it
+        let parameters = SwitchParameters { threshold: 2.5, hysteresis: 0.5, on_resistance: 1.0, off_resistance: 1e9 };
+        let mut circ = Circuit::new(0);
+        let triangle = Waveform::Pwl(vec![(0.0, 0.0), (1e-3, 5.0), (2e-3, 0.0)]);
+        circ.add_bipole_between(Box::new(WaveformVoltageSource::new(triangle)), "ctl", "0", String::from("V1"));
+        circ.add_bipole_between(Box::new(VoltageSource::new(1.0)), "in", "0", String::from("V2"));
+        circ.add_bipole_between(Box::new(Resistor::new(1e3)), "in", "out", String::from("R1"));
+        circ.add_device_between(Box::new(VoltageSwitch::new(parameters, false)), &["out", "0", "ctl", "0"],
+            String::from("S1"));
+
+        let options = SimulationOptions { newton: NewtonOptions { vntol: 1.0, ..Default::default() }, ..Default::default() };
+        let out = circ.simulate_with_options(2e-3, 0.1e-3, &options).unwrap();
+        let voltage = out.voltage("out").unwrap();
+        for (sample, closed) in [(6, false), (7, true), (16, true), (17, false)] {
+            let expected = if closed { 1.0/1001.0 } else { 1e9/(1e9 + 1e3) };
+            assert!((voltage[sample] - expected).abs() < 1e-9, "{sample}");
+        }
+    }
+
+    #[test]
+    fn test_newton_overshoot() {
+        // linearised at its critical voltage of 0.7 V the diode fed through 10 ohm overshoots
+        // to 1.2 V at an iterate, beyond the threshold, while it settles below 0.8 V
+        let parameters = SwitchParameters { threshold: 1.0, hysteresis: 0.1, on_resistance: 1.0, off_resistance: 1e9 };
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(VoltageSource::new(5.0)), "in", "0", String::from("V1"));
+        circ.add_bipole_between(Box::new(Resistor::new(10.0)), "in", "d", String::from("R1"));
+        circ.add_bipole_between(Box::new(Diode::new(1e-14, 0.025, 1.08, 0.9)), "d", "0", String::from("D1"));
+        circ.add_bipole_between(Box::new(Resistor::new(1e3)), "in", "out", String::from("R2"));
+        circ.add_device_between(Box::new(VoltageSwitch::new(parameters, false)), &["out", "0", "d", "0"],
+            String::from("S1"));
+
+        let op = circ.operating_point().unwrap();
+        assert!(op.voltage("d").unwrap() < 0.8);
+        assert!((op.voltage("out").unwrap() - 5.0 * 1e9/(1e9 + 1e3)).abs() < 1e-9);
+        let out = circ.simulate(1e-3, 1e-4).unwrap();
+        assert!(out.voltage("out").unwrap().iter().all(|voltage| (voltage - 5.0 * 1e9/(1e9 + 1e3)).abs() < 1e-9));
+    }
+
+    #[test]
+    fn test_relaxation_oscillator() {
+        // the capacitor charges towards 5 V through 1k until it closes the switch across it at
+        // 3 V, then discharges through 100 ohm until the switch opens at 2 V; the switch
+        // immediately pulls its own control back, which must not make it chatter
+        let parameters = SwitchParameters { threshold: 2.5, hysteresis: 0.5, on_resistance: 100.0, off_resistance: 1e9 };
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(VoltageSource::new(5.0)), "in", "0", String::from("V1"));
+        circ.add_bipole_between(Box::new(Resistor::new(1e3)), "in", "c", String::from("R1"));
+        circ.add_bipole_between(Box::new(Capacitor::new(1e-6, 0.0)), "c", "0", String::from("C1"));
+        circ.add_device_between(Box::new(VoltageSwitch::new(parameters, false)), &["c", "0", "c", "0"],
+            String::from("S1"));
+
+        let options = SimulationOptions { method: IntegrationMethod::Trapezoidal,
+            timestep_control: Some(TimestepControl::new(1e-9, 1e-5)), ..Default::default() };
+        let out = circ.simulate_with_options(3e-3, 1e-6, &options).unwrap();
+        let time = out.time.iter().copied().collect::<Vec<f64>>();
+        let voltage = out.voltage("c").unwrap().iter().copied().collect::<Vec<f64>>();
+
+        // the charge from 2 V to 3 V takes ln(1.5) ms and the discharge through 1k || 100 towards
+        // 5/11 V takes 1/11 ms * ln(2.5455/1.5455)
+        let discharge = 1e-3/11.0 * ((3.0f64 - 5.0/11.0)/(2.0 - 5.0/11.0)).ln();
+        let period = 1.5f64.ln() * 1e-3 + discharge;
+        let peaks = (1..voltage.len() - 1).filter(|&sample| voltage[sample] > voltage[sample - 1]
+            && voltage[sample] >= voltage[sample + 1]).collect::<Vec<usize>>();
+        let troughs = (1..voltage.len() - 1).filter(|&sample| voltage[sample] < voltage[sample - 1]
+            && voltage[sample] <= voltage[sample + 1]).collect::<Vec<usize>>();
+        assert!(peaks.len() >= 5);
+        assert_eq!(peaks.len(), troughs.len());
+        for &peak in &peaks {
+            assert!((voltage[peak] - 3.0).abs() < 0.01, "{}", time[peak]);
+        }
+        for &trough in &troughs {
+            assert!((voltage[trough] - 2.0).abs() < 0.01, "{}", time[trough]);
+        }
+        for pair in peaks.windows(2) {
+            assert!((time[pair[1]] - time[pair[0]] - period).abs() < 0.01 * period);
+        }
+        for (&peak, &trough) in peaks.iter().zip(&troughs) {
+            assert!((time[trough] - time[peak] - discharge).abs() < 0.02 * discharge);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! Structural checks on the circuit graph, run before the MNA system is built:
+//! nodes without a DC path to ground, loops made only of voltage-defined branches
+//! and cutsets made only of current-defined branches all make the matrix singular.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BranchKind {
+    Conductive,
+    /// Voltage sources, and inductors at DC.
+    VoltageDefined,
+    CurrentSource,
+    /// Capacitors at DC.
+    Open
+}
+
+pub(crate) struct Branch<'a> {
+    pub name: &'a str,
+    pub anode_id: usize,
+    pub catode_id: usize,
+    pub kind: BranchKind
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TopologyDiagnostic {
+    /// Nodes joined to the rest of the circuit by capacitors only, or not at all.
+    NoDcPath { nodes: Vec<usize>, bipoles: Vec<String> },
+    /// A loop of voltage sources and inductors, whose currents are undetermined.
+    VoltageLoop { nodes: Vec<usize>, bipoles: Vec<String> },
+    /// A cutset of current sources and capacitors, which forces the sum of the source currents.
+    CurrentCutset { nodes: Vec<usize>, bipoles: Vec<String> }
+}
+
+impl TopologyDiagnostic {
+    pub fn nodes(&self) -> &[usize] {
+        match self {
+            TopologyDiagnostic::NoDcPath { nodes, .. }
+            | TopologyDiagnostic::VoltageLoop { nodes, .. }
+            | TopologyDiagnostic::CurrentCutset { nodes, .. } => nodes
+        }
+    }
+
+    pub fn bipoles(&self) -> &[String] {
+        match self {
+            TopologyDiagnostic::NoDcPath { bipoles, .. }
+            | TopologyDiagnostic::VoltageLoop { bipoles, .. }
+            | TopologyDiagnostic::CurrentCutset { bipoles, .. } => bipoles
+        }
+    }
+}
+
+impl fmt::Display for TopologyDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TopologyDiagnostic::NoDcPath { nodes, bipoles } if bipoles.is_empty() =>
+                write!(f, "nodes {nodes:?} are not connected to ground"),
+            TopologyDiagnostic::NoDcPath { nodes, bipoles } =>
+                write!(f, "nodes {nodes:?} have no DC path to ground, only through {}", bipoles.join(", ")),
+            TopologyDiagnostic::VoltageLoop { bipoles, .. } =>
+                write!(f, "loop of voltage sources and inductors: {}", bipoles.join(", ")),
+            TopologyDiagnostic::CurrentCutset { nodes, bipoles } =>
+                write!(f, "nodes {nodes:?} are fed only by current sources and capacitors: {}", bipoles.join(", "))
+        }
+    }
+}
+
+struct DisjointSet {
+    parent: Vec<usize>
+}
+
+impl DisjointSet {
+    fn new(size: usize) -> DisjointSet {
+        DisjointSet { parent: (0..size).collect() }
+    }
+
+    fn find(&mut self, mut item: usize) -> usize {
+        while self.parent[item] != item {
+            self.parent[item] = self.parent[self.parent[item]];
+            item = self.parent[item];
+        }
+        item
+    }
+
+    fn union(&mut self, first: usize, second: usize) -> bool {
+        let (first, second) = (self.find(first), self.find(second));
+        self.parent[first] = second;
+        first != second
+    }
+}
+
+/// Runs every check; `nodes` must hold the dense ids `0..nodes` and branches are reported in the given order.
+pub(crate) fn check(branches: &[Branch], nodes: usize, ground_id: usize) -> Vec<TopologyDiagnostic> {
+    let mut diagnostics = voltage_loops(branches, nodes);
+    diagnostics.extend(isolated_groups(branches, nodes, ground_id));
+    diagnostics
+}
+
+fn voltage_loops(branches: &[Branch], nodes: usize) -> Vec<TopologyDiagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut sets = DisjointSet::new(nodes);
+    let mut forest: HashMap<usize, Vec<(usize, usize)>> = HashMap::new();
+
+    for (index, branch) in branches.iter().enumerate() {
+        if branch.kind != BranchKind::VoltageDefined {
+            continue;
+        }
+        if sets.union(branch.anode_id, branch.catode_id) {
+            forest.entry(branch.anode_id).or_default().push((branch.catode_id, index));
+            forest.entry(branch.catode_id).or_default().push((branch.anode_id, index));
+            continue;
+        }
+
+        let mut loop_branches = forest_path(&forest, branch.anode_id, branch.catode_id);
+        loop_branches.push(index);
+        let mut loop_nodes: Vec<usize> = loop_branches.iter()
+            .flat_map(|index| [branches[*index].anode_id, branches[*index].catode_id])
+            .collect();
+        loop_nodes.sort();
+        loop_nodes.dedup();
+        diagnostics.push(TopologyDiagnostic::VoltageLoop {
+            nodes: loop_nodes,
+            bipoles: loop_branches.iter().map(|index| String::from(branches[*index].name)).collect()
+        });
+    }
+    diagnostics
+}
+
+/// Branch indices along the unique path between two nodes of a forest.
+fn forest_path(forest: &HashMap<usize, Vec<(usize, usize)>>, from: usize, to: usize) -> Vec<usize> {
+    let mut reached: HashMap<usize, (usize, usize)> = HashMap::new();
+    let mut queue = VecDeque::from([from]);
+    while let Some(node) = queue.pop_front() {
+        if node == to {
+            break;
+        }
+        for (next, index) in forest.get(&node).into_iter().flatten() {
+            if *next != from && !reached.contains_key(next) {
+                reached.insert(*next, (node, *index));
+                queue.push_back(*next);
+            }
+        }
+    }
+
+    let mut path = Vec::new();
+    let mut node = to;
+    while node != from {
+        let (previous, index) = reached[&node];
+        path.push(index);
+        node = previous;
+    }
+    path.reverse();
+    path
+}
+
+/// Groups of nodes cut off from ground once current sources and open branches are removed.
+fn isolated_groups(branches: &[Branch], nodes: usize, ground_id: usize) -> Vec<TopologyDiagnostic> {
+    let mut sets = DisjointSet::new(nodes);
+    for branch in branches {
+        if matches!(branch.kind, BranchKind::Conductive | BranchKind::VoltageDefined) {
+            sets.union(branch.anode_id, branch.catode_id);
+        }
+    }
+
+    let ground_root = sets.find(ground_id);
+    let mut groups: Vec<(usize, Vec<usize>)> = Vec::new();
+    for node in 0..nodes {
+        let root = sets.find(node);
+        if root == ground_root {
+            continue;
+        }
+        match groups.iter_mut().find(|(group_root, _)| *group_root == root) {
+            Some((_, group)) => group.push(node),
+            None => groups.push((root, vec![node]))
+        }
+    }
+
+    groups.into_iter().map(|(root, group)| {
+        let mut cut = Vec::new();
+        let mut has_source = false;
+        for branch in branches {
+            let inside = (sets.find(branch.anode_id) == root, sets.find(branch.catode_id) == root);
+            if inside.0 != inside.1 {
+                has_source |= branch.kind == BranchKind::CurrentSource;
+                cut.push(String::from(branch.name));
+            }
+        }
+
+        if has_source {
+            TopologyDiagnostic::CurrentCutset { nodes: group, bipoles: cut }
+        } else {
+            TopologyDiagnostic::NoDcPath { nodes: group, bipoles: cut }
+        }
+    }).collect()
+}
@@ -0,0 +1,916 @@
+//! Bipolar and MOS transistors, as three-terminal devices; a MOSFET may also expose its bulk.
+
+use std::f64::consts;
+use mathru::algebra::abstr::Complex;
+
+use crate::bipoles::{check_finite, check_positive, limit_junction_voltage, IntegrationMethod, NewtonOptions,
+    TimestepControl, GMIN};
+use crate::devices::{Device, Solution, Stamper, Step};
+use crate::netlist::THERMAL_VOLTAGE;
+use crate::sparse::Element;
+use crate::topology::BranchKind;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    Npn,
+    Pnp
+}
+
+impl Polarity {
+    fn sign(&self) -> f64 {
+        match self {
+            Polarity::Npn => 1.0,
+            Polarity::Pnp => -1.0
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    N,
+    P
+}
+
+impl Channel {
+    fn sign(&self) -> f64 {
+        match self {
+            Channel::N => 1.0,
+            Channel::P => -1.0
+        }
+    }
+}
+
+/// Built-in potential, grading coefficient and forward-bias coefficient of the junction
+/// capacitances, at their SPICE defaults.
+const JUNCTION_POTENTIAL: f64 = 0.75;
+const GRADING_COEFFICIENT: f64 = 0.33;
+const FORWARD_BIAS_COEFFICIENT: f64 = 0.5;
+
+/// Depletion charge of a junction and its integration history; above
+/// `FORWARD_BIAS_COEFFICIENT * JUNCTION_POTENTIAL` the capacitance is extended linearly, as in SPICE.
+/// With a grading coefficient of zero the capacitance is constant.
+#[derive(Clone)]
+struct DepletionCharge {
+    capacitance_j0: f64,
+    grading: f64,
+    charge: f64,
+    current: f64,
+    previous_charge: f64,
+    older_charge: f64,
+    previous_timestep_sec: Option<f64>,
+    older_timestep_sec: Option<f64>
+}
+
+impl DepletionCharge {
+    fn new(capacitance_j0: f64) -> DepletionCharge {
+        DepletionCharge { capacitance_j0, grading: GRADING_COEFFICIENT, charge: 0.0, current: 0.0,
+            previous_charge: 0.0, older_charge: 0.0, previous_timestep_sec: None, older_timestep_sec: None }
+    }
+
+    fn linear(capacitance: f64) -> DepletionCharge {
+        DepletionCharge { grading: 0.0, ..DepletionCharge::new(capacitance) }
+    }
+
+    fn capacitance(&self, voltage: f64) -> f64 {
+        let (potential, grading) = (JUNCTION_POTENTIAL, self.grading);
+        if voltage < FORWARD_BIAS_COEFFICIENT * potential {
+            self.capacitance_j0 * (1.0 - voltage/potential).powf(-grading)
+        } else {
+            self.capacitance_j0 * (1.0 - FORWARD_BIAS_COEFFICIENT * (1.0 + grading) + grading * voltage/potential)
+                / (1.0 - FORWARD_BIAS_COEFFICIENT).powf(1.0 + grading)
+        }
+    }
+
+    fn charge(&self, voltage: f64) -> f64 {
+        let (potential, grading) = (JUNCTION_POTENTIAL, self.grading);
+        let depletion = |voltage: f64|
+            self.capacitance_j0 * potential * (1.0 - (1.0 - voltage/potential).powf(1.0 - grading))/(1.0 - grading);
+        let knee = FORWARD_BIAS_COEFFICIENT * potential;
+        if voltage < knee {
+            return depletion(voltage);
+        }
+        depletion(knee) + self.capacitance_j0/(1.0 - FORWARD_BIAS_COEFFICIENT).powf(1.0 + grading)
+            * ((1.0 - FORWARD_BIAS_COEFFICIENT * (1.0 + grading)) * (voltage - knee)
+                + grading/(2.0 * potential) * (voltage * voltage - knee * knee))
+    }
+
+    /// Conductance and current source of the charging current at `voltage`.
+    fn companion(&self, voltage: f64, timestep_sec: f64, method: IntegrationMethod) -> (f64, f64) {
+        let (charge, capacitance) = (self.charge(voltage), self.capacitance(voltage));
+        let (conduttance, current) = if method == IntegrationMethod::Trapezoidal && self.previous_timestep_sec.is_some() {
+            (2.0 * capacitance/timestep_sec, 2.0 * (charge - self.charge)/timestep_sec - self.current)
+        } else {
+            let (a0, a1, a2) = method.bdf_coefficients(timestep_sec, self.previous_timestep_sec);
+            (a0 * capacitance/timestep_sec, (a0 * charge + a1 * self.charge + a2 * self.previous_charge)/timestep_sec)
+        };
+        (conduttance, current - conduttance * voltage)
+    }
+
+    fn update_state(&mut self, voltage: f64, timestep_sec: f64, method: IntegrationMethod) {
+        let (conduttance, current) = self.companion(voltage, timestep_sec, method);
+        self.current = conduttance * voltage + current;
+        self.older_charge = self.previous_charge;
+        self.previous_charge = self.charge;
+        self.charge = self.charge(voltage);
+        self.older_timestep_sec = self.previous_timestep_sec;
+        self.previous_timestep_sec = Some(timestep_sec);
+    }
+
+    fn truncation_error_ratio(&self, voltage: f64, timestep_sec: f64,
+        method: IntegrationMethod, control: &TimestepControl) -> f64 {
+
+        let charges = [self.charge(voltage), self.charge, self.previous_charge, self.older_charge];
+        let timesteps_sec: Vec<f64> = [Some(timestep_sec), self.previous_timestep_sec, self.older_timestep_sec]
+            .into_iter().map_while(|timestep_sec| timestep_sec).collect();
+
+        control.error_ratio(method, &charges[..timesteps_sec.len() + 1], &timesteps_sec)
+    }
+}
+
+/// Gummel-Poon parameters of a bipolar transistor. With the defaults (no Early effect and no
+/// junction capacitance) the model reduces to the Ebers-Moll transport model.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BjtParameters {
+    /// Transport saturation current (IS).
+    pub current_s: f64,
+    /// Ideal forward current gain (BF).
+    pub beta_f: f64,
+    /// Ideal reverse current gain (BR).
+    pub beta_r: f64,
+    /// Forward Early voltage (VAF); infinite when there is no Early effect.
+    pub early_voltage: f64,
+    /// Zero-bias base-emitter depletion capacitance (CJE).
+    pub capacitance_je: f64,
+    /// Zero-bias base-collector depletion capacitance (CJC).
+    pub capacitance_jc: f64,
+    pub voltage_vt: f64
+}
+
+impl Default for BjtParameters {
+    fn default() -> Self {
+        BjtParameters { current_s: 1e-16, beta_f: 100.0, beta_r: 1.0, early_voltage: f64::INFINITY,
+            capacitance_je: 0.0, capacitance_jc: 0.0, voltage_vt: THERMAL_VOLTAGE }
+    }
+}
+
+/// Currents into the first two terminals, each with its derivatives by the `N` controlling
+/// voltages of the device; all in the NPN or N-channel orientation. The third terminal
+/// carries the current that balances them.
+struct Linearisation<const N: usize> {
+    currents: [f64; 2],
+    conductances: [[f64; N]; 2]
+}
+
+impl<const N: usize> Linearisation<N> {
+    /// Current sources of the companion model around `voltages`, turned to the actual
+    /// orientation by `sign`.
+    fn equivalent_currents(&self, voltages: [f64; N], sign: f64) -> [f64; 2] {
+        [0, 1].map(|terminal| sign * self.conductances[terminal].iter().zip(voltages)
+            .fold(self.currents[terminal], |current, (conductance, voltage)| current - conductance * voltage))
+    }
+}
+
+/// Controlling voltages `v(positive) - v(negative)` of a solution, for pairs of terminals.
+fn controlling_voltages<T: Element, const N: usize>(solution: &Solution<T>, controls: [(usize, usize); N]) -> [T; N] {
+    controls.map(|(positive, negative)| solution.voltage(positive) - solution.voltage(negative))
+}
+
+/// Currents into the three terminals of a device whose first two take `currents` plus
+/// `conductances` times the controlling voltages.
+fn terminal_currents<T: Element, const N: usize>(conductances: [[T; N]; 2], currents: [T; 2], voltages: [T; N]) -> [T; 3] {
+    let [first, second] = [0, 1].map(|terminal| conductances[terminal].into_iter().zip(voltages)
+        .fold(currents[terminal], |current, (conductance, voltage)| current + conductance * voltage));
+    [first, second, T::zero() - first - second]
+}
+
+/// Stamps the currents of `terminal_currents`, with the controlling voltages taken between the
+/// pairs of terminals in `controls`.
+fn stamp_terminals<T: Element, const N: usize>(stamper: &mut Stamper<T>, controls: [(usize, usize); N],
+    conductances: [[T; N]; 2], currents: [T; 2]) {
+    let zero = T::zero();
+    let balancing = std::array::from_fn(|control| zero - conductances[0][control] - conductances[1][control]);
+
+    for (terminal, row_conductances, current) in [(0, conductances[0], currents[0]), (1, conductances[1], currents[1]),
+        (2, balancing, zero - currents[0] - currents[1])] {
+        let row = stamper.node(terminal);
+        for ((positive, negative), conductance) in controls.into_iter().zip(row_conductances) {
+            stamper.add(row, stamper.node(positive), conductance);
+            stamper.add(row, stamper.node(negative), zero - conductance);
+        }
+        stamper.add_source(row, zero - current);
+    }
+}
+
+/// Bipolar junction transistor with terminals collector, base and emitter.
+#[derive(Clone)]
+pub struct Bjt {
+    polarity: Polarity,
+    parameters: BjtParameters,
+    /// Junction voltages of the last Newton iterate, positive when forward biased.
+    voltage_be: f64,
+    voltage_bc: f64,
+    charge_be: DepletionCharge,
+    charge_bc: DepletionCharge
+}
+
+impl Bjt {
+    pub fn new(polarity: Polarity, parameters: BjtParameters) -> Bjt {
+        Bjt { polarity, parameters, voltage_be: 0.0, voltage_bc: 0.0,
+            charge_be: DepletionCharge::new(parameters.capacitance_je),
+            charge_bc: DepletionCharge::new(parameters.capacitance_jc) }
+    }
+
+    fn critical_voltage(&self) -> f64 {
+        let voltage_vt = self.parameters.voltage_vt;
+        voltage_vt * (voltage_vt/(consts::SQRT_2 * self.parameters.current_s)).ln()
+    }
+
+    /// The base-emitter and base-collector voltages control the collector and base currents.
+    const CONTROLS: [(usize, usize); 2] = [(1, 2), (1, 0)];
+
+    /// Base-emitter and base-collector voltages of a solution, before the polarity is applied.
+    fn junction_voltages<T: Element>(&self, solution: &Solution<T>) -> [T; 2] {
+        controlling_voltages(solution, Bjt::CONTROLS)
+    }
+
+    /// Transport-model currents, with the Gummel-Poon base charge reduced to the Early effect
+    /// and `GMIN` across both junctions as for the diode.
+    fn dc(&self, voltage_be: f64, voltage_bc: f64) -> Linearisation<2> {
+        let p = &self.parameters;
+        let (exp_be, exp_bc) = ((voltage_be/p.voltage_vt).exp(), (voltage_bc/p.voltage_vt).exp());
+        let forward = p.current_s * (exp_be - 1.0);
+        let reverse = p.current_s * (exp_bc - 1.0);
+        let (forward_g, reverse_g) = (p.current_s/p.voltage_vt * exp_be, p.current_s/p.voltage_vt * exp_bc);
+        let early = 1.0 - voltage_bc/p.early_voltage;
+
+        Linearisation {
+            currents: [
+                (forward - reverse) * early - reverse/p.beta_r - GMIN * voltage_bc,
+                forward/p.beta_f + reverse/p.beta_r + GMIN * (voltage_be + voltage_bc)
+            ],
+            conductances: [
+                [forward_g * early,
+                    -reverse_g * early - (forward - reverse)/p.early_voltage - reverse_g/p.beta_r - GMIN],
+                [forward_g/p.beta_f + GMIN, reverse_g/p.beta_r + GMIN]
+            ]
+        }
+    }
+
+    /// DC currents plus, in a transient step, the currents charging the junctions.
+    fn linearise(&self, step: Step) -> Linearisation<2> {
+        let mut model = self.dc(self.voltage_be, self.voltage_bc);
+        if let Step::Transient { timestep_sec, method, .. } = step {
+            let (conduttance_be, current_be) = self.charge_be.companion(self.voltage_be, timestep_sec, method);
+            let (conduttance_bc, current_bc) = self.charge_bc.companion(self.voltage_bc, timestep_sec, method);
+            let charging_bc = conduttance_bc * self.voltage_bc + current_bc;
+
+            // both charging currents enter at the base, the base-collector one leaves at the collector
+            model.currents[0] -= charging_bc;
+            model.conductances[0][1] -= conduttance_bc;
+            model.currents[1] += conduttance_be * self.voltage_be + current_be + charging_bc;
+            model.conductances[1][0] += conduttance_be;
+            model.conductances[1][1] += conduttance_bc;
+        }
+        model
+    }
+
+    /// Collector and base admittances by the junction voltages, with the depletion
+    /// capacitances at the last operating point.
+    fn admittances(&self, omega: f64) -> [[Complex<f64>; 2]; 2] {
+        let conductances = self.dc(self.voltage_be, self.voltage_bc).conductances;
+        let susceptance_be = omega * self.charge_be.capacitance(self.voltage_be);
+        let susceptance_bc = omega * self.charge_bc.capacitance(self.voltage_bc);
+        [
+            [Complex::new(conductances[0][0], 0.0), Complex::new(conductances[0][1], -susceptance_bc)],
+            [Complex::new(conductances[1][0], susceptance_be), Complex::new(conductances[1][1], susceptance_bc)]
+        ]
+    }
+}
+
+fn check_not_negative(parameter: &str, value: f64) -> Result<(), String> {
+    check_finite(parameter, value)?;
+    if value < 0.0 {
+        return Err(format!("{parameter} must not be negative, got {value}"));
+    }
+    Ok(())
+}
+
+impl Device for Bjt {
+    fn terminals(&self) -> &'static [&'static str] {
+        &["c", "b", "e"]
+    }
+
+    fn stamp(&self, step: Step, stamper: &mut Stamper<f64>) {
+        let model = self.linearise(step);
+        let currents = model.equivalent_currents([self.voltage_be, self.voltage_bc], self.polarity.sign());
+        stamp_terminals(stamper, Bjt::CONTROLS, model.conductances, currents);
+    }
+
+    fn stamp_ac(&self, omega: f64, stamper: &mut Stamper<Complex<f64>>) {
+        let zero = Complex::new(0.0, 0.0);
+        stamp_terminals(stamper, Bjt::CONTROLS, self.admittances(omega), [zero, zero]);
+    }
+
+    fn currents(&self, step: Step, solution: &Solution<f64>) -> Vec<f64> {
+        let model = self.linearise(step);
+        let currents = model.equivalent_currents([self.voltage_be, self.voltage_bc], self.polarity.sign());
+        terminal_currents(model.conductances, currents, self.junction_voltages(solution)).to_vec()
+    }
+
+    fn ac_currents(&self, omega: f64, solution: &Solution<Complex<f64>>) -> Vec<Complex<f64>> {
+        let zero = Complex::new(0.0, 0.0);
+        terminal_currents(self.admittances(omega), [zero, zero], self.junction_voltages(solution)).to_vec()
+    }
+
+    fn topology(&self, _step: Step) -> Vec<(usize, usize, BranchKind)> {
+        vec![(1, 2, BranchKind::Conductive), (1, 0, BranchKind::Conductive)]
+    }
+
+    fn is_dynamic(&self) -> bool {
+        self.parameters.capacitance_je > 0.0 || self.parameters.capacitance_jc > 0.0
+    }
+
+    fn is_nonlinear(&self) -> bool {
+        true
+    }
+
+    fn update_operating_point(&mut self, solution: &Solution<f64>) {
+        let [voltage_be, voltage_bc] = self.junction_voltages(solution);
+        let sign = self.polarity.sign();
+        let (voltage_vt, voltage_crit) = (self.parameters.voltage_vt, self.critical_voltage());
+        self.voltage_be = limit_junction_voltage(sign * voltage_be, self.voltage_be, voltage_vt, voltage_crit);
+        self.voltage_bc = limit_junction_voltage(sign * voltage_bc, self.voltage_bc, voltage_vt, voltage_crit);
+    }
+
+    fn reset_operating_point(&mut self, _newton: &NewtonOptions) {
+        self.voltage_be = self.critical_voltage();
+        self.voltage_bc = 0.0;
+    }
+
+    fn warm_start(&mut self, solution: &Solution<f64>, _newton: &NewtonOptions) {
+        let [voltage_be, voltage_bc] = self.junction_voltages(solution);
+        self.voltage_be = self.polarity.sign() * voltage_be;
+        self.voltage_bc = self.polarity.sign() * voltage_bc;
+    }
+
+    fn update_state(&mut self, solution: &Solution<f64>, timestep_sec: f64, method: IntegrationMethod) {
+        let [voltage_be, voltage_bc] = self.junction_voltages(solution);
+        let sign = self.polarity.sign();
+        self.charge_be.update_state(sign * voltage_be, timestep_sec, method);
+        self.charge_bc.update_state(sign * voltage_bc, timestep_sec, method);
+    }
+
+    fn truncation_error_ratio(&self, solution: &Solution<f64>, timestep_sec: f64,
+        method: IntegrationMethod, control: &TimestepControl) -> f64 {
+
+        let [voltage_be, voltage_bc] = self.junction_voltages(solution);
+        let sign = self.polarity.sign();
+        self.charge_be.truncation_error_ratio(sign * voltage_be, timestep_sec, method, control)
+            .max(self.charge_bc.truncation_error_ratio(sign * voltage_bc, timestep_sec, method, control))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let p = &self.parameters;
+        check_positive("saturation current", p.current_s)?;
+        check_positive("forward beta", p.beta_f)?;
+        check_positive("reverse beta", p.beta_r)?;
+        check_positive("thermal voltage", p.voltage_vt)?;
+        if p.early_voltage.is_nan() || p.early_voltage <= 0.0 {
+            return Err(format!("Early voltage must be positive, got {}", p.early_voltage));
+        }
+        check_not_negative("base-emitter capacitance", p.capacitance_je)?;
+        check_not_negative("base-collector capacitance", p.capacitance_jc)
+    }
+}
+
+/// Level-1 (Shichman-Hodges) parameters of a MOSFET.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MosfetParameters {
+    /// Zero-bias threshold voltage (VTO), negative for an enhancement PMOS.
+    pub threshold_voltage: f64,
+    /// Process transconductance (KP).
+    pub transconductance: f64,
+    /// Channel-length modulation (LAMBDA).
+    pub channel_length_modulation: f64,
+    /// Body-effect coefficient (GAMMA); only used with a bulk terminal.
+    pub body_effect: f64,
+    /// Surface potential (PHI).
+    pub surface_potential: f64,
+    pub width: f64,
+    pub length: f64,
+    /// Gate-source and gate-drain overlap capacitances per unit width (CGSO, CGDO).
+    pub overlap_capacitance_gs: f64,
+    pub overlap_capacitance_gd: f64
+}
+
+impl Default for MosfetParameters {
+    fn default() -> Self {
+        MosfetParameters { threshold_voltage: 0.0, transconductance: 2e-5, channel_length_modulation: 0.0,
+            body_effect: 0.0, surface_potential: 0.6, width: 1e-4, length: 1e-4,
+            overlap_capacitance_gs: 0.0, overlap_capacitance_gd: 0.0 }
+    }
+}
+
+/// Drain current with its derivatives by the gate-source, drain-source and bulk-source voltages.
+struct DrainCurrent {
+    current: f64,
+    gm: f64,
+    gds: f64,
+    gmbs: f64
+}
+
+/// Limits the Newton step of a gate voltage around the threshold, as `fetlim` in SPICE.
+fn limit_gate_voltage(voltage: f64, previous: f64, threshold: f64) -> f64 {
+    let step_high = (2.0 * (previous - threshold)).abs() + 2.0;
+    let step_low = step_high/2.0 + 2.0;
+    let fully_on = threshold + 3.5;
+    let delta = voltage - previous;
+
+    if previous >= threshold {
+        if previous >= fully_on {
+            if delta <= 0.0 {
+                if voltage >= fully_on {
+                    if -delta > step_low { previous - step_low } else { voltage }
+                } else {
+                    voltage.max(threshold + 2.0)
+                }
+            } else if delta >= step_high {
+                previous + step_high
+            } else {
+                voltage
+            }
+        } else if delta <= 0.0 {
+            voltage.max(threshold - 0.5)
+        } else {
+            voltage.min(threshold + 4.0)
+        }
+    } else if delta <= 0.0 {
+        if -delta > step_high { previous - step_high } else { voltage }
+    } else if voltage <= threshold + 0.5 {
+        if delta > step_low { previous + step_low } else { voltage }
+    } else {
+        threshold + 0.5
+    }
+}
+
+/// Limits the Newton step of a drain-source voltage, as `limvds` in SPICE.
+fn limit_drain_voltage(voltage: f64, previous: f64) -> f64 {
+    if previous >= 3.5 {
+        if voltage > previous {
+            voltage.min(3.0 * previous + 2.0)
+        } else if voltage < 3.5 {
+            voltage.max(2.0)
+        } else {
+            voltage
+        }
+    } else if voltage > previous {
+        voltage.min(4.0)
+    } else {
+        voltage.max(-0.5)
+    }
+}
+
+/// Level-1 MOSFET with terminals drain, gate and source, and bulk when built `with_bulk`.
+/// Drain and source swap roles when the drain-source voltage reverses.
+#[derive(Clone)]
+pub struct Mosfet {
+    channel: Channel,
+    parameters: MosfetParameters,
+    has_bulk: bool,
+    /// Gate-source, drain-source and bulk-source voltages of the last Newton iterate, in the
+    /// N-channel orientation.
+    voltages: [f64; 3],
+    charge_gs: DepletionCharge,
+    charge_gd: DepletionCharge
+}
+
+impl Mosfet {
+    /// A transistor whose bulk is tied to the source.
+    pub fn new(channel: Channel, parameters: MosfetParameters) -> Mosfet {
+        Mosfet { channel, parameters, has_bulk: false, voltages: [0.0; 3],
+            charge_gs: DepletionCharge::linear(parameters.overlap_capacitance_gs * parameters.width),
+            charge_gd: DepletionCharge::linear(parameters.overlap_capacitance_gd * parameters.width) }
+    }
+
+    pub fn with_bulk(channel: Channel, parameters: MosfetParameters) -> Mosfet {
+        Mosfet { has_bulk: true, ..Mosfet::new(channel, parameters) }
+    }
+
+    fn bulk(&self) -> usize {
+        if self.has_bulk { 3 } else { 2 }
+    }
+
+    /// The gate-source, drain-source and bulk-source voltages control the drain and gate currents.
+    fn controls(&self) -> [(usize, usize); 3] {
+        [(1, 2), (0, 2), (self.bulk(), 2)]
+    }
+
+    /// Gate-source, drain-source and bulk-source voltages of a solution, before the channel is applied.
+    fn controlling_voltages<T: Element>(&self, solution: &Solution<T>) -> [T; 3] {
+        controlling_voltages(solution, self.controls())
+    }
+
+    /// Threshold voltage and its derivative by the bulk-source voltage. A forward-biased bulk
+    /// lowers the threshold along the SPICE approximation, which never reaches the square root's pole.
+    fn threshold(&self, voltage_bs: f64) -> (f64, f64) {
+        let p = &self.parameters;
+        let sqrt_phi = p.surface_potential.sqrt();
+        let (root, derivative) = if voltage_bs <= 0.0 {
+            let root = (p.surface_potential - voltage_bs).sqrt();
+            (root, -0.5/root)
+        } else {
+            let root = sqrt_phi/(1.0 + 0.5 * voltage_bs/p.surface_potential);
+            (root, -0.5 * root * root/(p.surface_potential * sqrt_phi))
+        };
+        (self.channel.sign() * p.threshold_voltage + p.body_effect * (root - sqrt_phi), p.body_effect * derivative)
+    }
+
+    /// Square-law drain current with the drain above the source.
+    fn forward(&self, voltage_gs: f64, voltage_ds: f64, voltage_bs: f64) -> DrainCurrent {
+        let p = &self.parameters;
+        let beta = p.transconductance * p.width/p.length;
+        let lambda = p.channel_length_modulation;
+        let (threshold, threshold_derivative) = self.threshold(voltage_bs);
+        let overdrive = voltage_gs - threshold;
+        let modulation = 1.0 + lambda * voltage_ds;
+
+        let (current, gm, gds) = if overdrive <= 0.0 {
+            (0.0, 0.0, 0.0)
+        } else if voltage_ds < overdrive {
+            let current = beta * (overdrive - voltage_ds/2.0) * voltage_ds;
+            (current * modulation, beta * voltage_ds * modulation, beta * (overdrive - voltage_ds) * modulation + current * lambda)
+        } else {
+            let current = beta/2.0 * overdrive * overdrive;
+            (current * modulation, beta * overdrive * modulation, current * lambda)
+        };
+        DrainCurrent { current, gm, gds, gmbs: -gm * threshold_derivative }
+    }
+
+    /// Drain current in either direction, plus `GMIN` between drain and source.
+    fn dc(&self, [voltage_gs, voltage_ds, voltage_bs]: [f64; 3]) -> DrainCurrent {
+        let mut drain = if voltage_ds >= 0.0 {
+            self.forward(voltage_gs, voltage_ds, voltage_bs)
+        } else {
+            let reverse = self.forward(voltage_gs - voltage_ds, -voltage_ds, voltage_bs - voltage_ds);
+            DrainCurrent { current: -reverse.current, gm: -reverse.gm, gds: reverse.gm + reverse.gds + reverse.gmbs,
+                gmbs: -reverse.gmbs }
+        };
+        drain.current += GMIN * voltage_ds;
+        drain.gds += GMIN;
+        drain
+    }
+
+    /// Drain and gate currents, with the gate charging currents in a transient step.
+    fn linearise(&self, step: Step) -> Linearisation<3> {
+        let drain = self.dc(self.voltages);
+        let mut model = Linearisation {
+            currents: [drain.current, 0.0],
+            conductances: [[drain.gm, drain.gds, drain.gmbs], [0.0; 3]]
+        };
+        if let Step::Transient { timestep_sec, method, .. } = step {
+            let [voltage_gs, voltage_ds, _] = self.voltages;
+            let voltage_gd = voltage_gs - voltage_ds;
+            let (conduttance_gs, current_gs) = self.charge_gs.companion(voltage_gs, timestep_sec, method);
+            let (conduttance_gd, current_gd) = self.charge_gd.companion(voltage_gd, timestep_sec, method);
+            let charging_gd = conduttance_gd * voltage_gd + current_gd;
+
+            // both charging currents enter at the gate, the gate-drain one leaves at the drain
+            model.currents[0] -= charging_gd;
+            model.conductances[0][0] -= conduttance_gd;
+            model.conductances[0][1] += conduttance_gd;
+            model.currents[1] += conduttance_gs * voltage_gs + current_gs + charging_gd;
+            model.conductances[1][0] += conduttance_gs + conduttance_gd;
+            model.conductances[1][1] -= conduttance_gd;
+        }
+        model
+    }
+
+    /// Drain and gate admittances by the controlling voltages at the last operating point; the
+    /// overlap capacitances are linear.
+    fn admittances(&self, omega: f64) -> [[Complex<f64>; 3]; 2] {
+        let drain = self.dc(self.voltages);
+        let susceptance_gs = omega * self.charge_gs.capacitance(0.0);
+        let susceptance_gd = omega * self.charge_gd.capacitance(0.0);
+        [
+            [Complex::new(drain.gm, -susceptance_gd), Complex::new(drain.gds, susceptance_gd), Complex::new(drain.gmbs, 0.0)],
+            [Complex::new(0.0, susceptance_gs + susceptance_gd), Complex::new(0.0, -susceptance_gd), Complex::new(0.0, 0.0)]
+        ]
+    }
+}
+
+impl Device for Mosfet {
+    fn terminals(&self) -> &'static [&'static str] {
+        if self.has_bulk { &["d", "g", "s", "b"] } else { &["d", "g", "s"] }
+    }
+
+    fn stamp(&self, step: Step, stamper: &mut Stamper<f64>) {
+        let model = self.linearise(step);
+        let currents = model.equivalent_currents(self.voltages, self.channel.sign());
+        stamp_terminals(stamper, self.controls(), model.conductances, currents);
+    }
+
+    fn stamp_ac(&self, omega: f64, stamper: &mut Stamper<Complex<f64>>) {
+        let zero = Complex::new(0.0, 0.0);
+        stamp_terminals(stamper, self.controls(), self.admittances(omega), [zero, zero]);
+    }
+
+    fn currents(&self, step: Step, solution: &Solution<f64>) -> Vec<f64> {
+        let model = self.linearise(step);
+        let currents = model.equivalent_currents(self.voltages, self.channel.sign());
+        let mut currents = terminal_currents(model.conductances, currents, self.controlling_voltages(solution)).to_vec();
+        if self.has_bulk {
+            currents.push(0.0);
+        }
+        currents
+    }
+
+    fn ac_currents(&self, omega: f64, solution: &Solution<Complex<f64>>) -> Vec<Complex<f64>> {
+        let zero = Complex::new(0.0, 0.0);
+        let mut currents = terminal_currents(self.admittances(omega), [zero, zero], self.controlling_voltages(solution)).to_vec();
+        if self.has_bulk {
+            currents.push(zero);
+        }
+        currents
+    }
+
+    fn topology(&self, _step: Step) -> Vec<(usize, usize, BranchKind)> {
+        let mut pairs = vec![(0, 2, BranchKind::Conductive), (1, 2, BranchKind::Open)];
+        if self.has_bulk {
+            pairs.push((3, 2, BranchKind::Open));
+        }
+        pairs
+    }
+
+    fn is_dynamic(&self) -> bool {
+        self.parameters.overlap_capacitance_gs > 0.0 || self.parameters.overlap_capacitance_gd > 0.0
+    }
+
+    fn is_nonlinear(&self) -> bool {
+        true
+    }
+
+    fn update_operating_point(&mut self, solution: &Solution<f64>) {
+        let [voltage_gs, voltage_ds, voltage_bs] = self.controlling_voltages(solution).map(|voltage| self.channel.sign() * voltage);
+        let [previous_gs, previous_ds, previous_bs] = self.voltages;
+
+        // the gate is limited against whichever end currently acts as the source, with the
+        // threshold of the bulk voltage to that end
+        let (voltage_gs, voltage_ds) = if previous_ds >= 0.0 {
+            let threshold = self.threshold(previous_bs).0;
+            (limit_gate_voltage(voltage_gs, previous_gs, threshold), limit_drain_voltage(voltage_ds, previous_ds))
+        } else {
+            let threshold = self.threshold(previous_bs - previous_ds).0;
+            let voltage_gd = limit_gate_voltage(voltage_gs - voltage_ds, previous_gs - previous_ds, threshold);
+            let voltage_ds = -limit_drain_voltage(-voltage_ds, -previous_ds);
+            (voltage_gd + voltage_ds, voltage_ds)
+        };
+        self.voltages = [voltage_gs, voltage_ds, voltage_bs];
+    }
+
+    fn reset_operating_point(&mut self, _newton: &NewtonOptions) {
+        self.voltages = [self.threshold(0.0).0, 0.0, 0.0];
+    }
+
+    fn warm_start(&mut self, solution: &Solution<f64>, _newton: &NewtonOptions) {
+        self.voltages = self.controlling_voltages(solution).map(|voltage| self.channel.sign() * voltage);
+    }
+
+    fn update_state(&mut self, solution: &Solution<f64>, timestep_sec: f64, method: IntegrationMethod) {
+        let [voltage_gs, voltage_ds, _] = self.controlling_voltages(solution).map(|voltage| self.channel.sign() * voltage);
+        self.charge_gs.update_state(voltage_gs, timestep_sec, method);
+        self.charge_gd.update_state(voltage_gs - voltage_ds, timestep_sec, method);
+    }
+
+    fn truncation_error_ratio(&self, solution: &Solution<f64>, timestep_sec: f64,
+        method: IntegrationMethod, control: &TimestepControl) -> f64 {
+
+        let [voltage_gs, voltage_ds, _] = self.controlling_voltages(solution).map(|voltage| self.channel.sign() * voltage);
+        self.charge_gs.truncation_error_ratio(voltage_gs, timestep_sec, method, control)
+            .max(self.charge_gd.truncation_error_ratio(voltage_gs - voltage_ds, timestep_sec, method, control))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let p = &self.parameters;
+        check_finite("threshold voltage", p.threshold_voltage)?;
+        check_positive("transconductance", p.transconductance)?;
+        check_not_negative("channel-length modulation", p.channel_length_modulation)?;
+        check_not_negative("body effect", p.body_effect)?;
+        check_positive("surface potential", p.surface_potential)?;
+        check_positive("width", p.width)?;
+        check_positive("length", p.length)?;
+        check_not_negative("gate-source overlap capacitance", p.overlap_capacitance_gs)?;
+        check_not_negative("gate-drain overlap capacitance", p.overlap_capacitance_gd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bipoles::{Circuit, OperatingPoint, Resistor, Sweep, VoltageSource};
+
+    /// Divider-biased common-emitter stage; a PNP runs from a negative supply.
+    fn common_emitter(polarity: Polarity) -> OperatingPoint {
+        let supply = 10.0 * polarity.sign();
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(VoltageSource::new(supply)), "vcc", "0", String::from("VCC"));
+        circ.add_bipole_between(Box::new(Resistor::new(47e3)), "vcc", "base", String::from("R1"));
+        circ.add_bipole_between(Box::new(Resistor::new(10e3)), "base", "0", String::from("R2"));
+        circ.add_bipole_between(Box::new(Resistor::new(4.7e3)), "vcc", "collector", String::from("RC"));
+        circ.add_bipole_between(Box::new(Resistor::new(1e3)), "emitter", "0", String::from("RE"));
+        circ.add_device_between(Box::new(Bjt::new(polarity, BjtParameters::default())),
+            &["collector", "base", "emitter"], String::from("Q1"));
+        circ.operating_point().unwrap()
+    }
+
+    #[test]
+    fn test_common_emitter_bias() {
+        let op = common_emitter(Polarity::Npn);
+        let (collector_i, base_i, emitter_i) = (op.currents["Q1.c"], op.currents["Q1.b"], op.currents["Q1.e"]);
+        let voltage_be = op.voltage("base").unwrap() - op.voltage("emitter").unwrap();
+        let parameters = BjtParameters::default();
+
+        // forward active: the collector current follows the base-emitter junction and is BF times the base current
+        assert!((collector_i/(parameters.current_s * (voltage_be/parameters.voltage_vt).exp()) - 1.0).abs() < 1e-3);
+        assert!((collector_i/base_i - parameters.beta_f).abs() < 1e-3);
+        assert!((collector_i + base_i + emitter_i).abs() < 1e-15);
+        // within the Newton tolerance, the emitter current is the one through RE
+        assert!((emitter_i + op.currents["RE"]).abs() < 1e-4 * collector_i);
+        // about 0.97 mA from a 1.75 V Thevenin base bias
+        assert!(collector_i > 0.9e-3 && collector_i < 1.0e-3);
+        assert!(op.voltage("collector").unwrap() - op.voltage("emitter").unwrap() > 1.0);
+
+        // the PNP stage is its mirror image
+        let mirrored = common_emitter(Polarity::Pnp);
+        for net in ["base", "collector", "emitter"] {
+            assert!((mirrored.voltage(net).unwrap() + op.voltage(net).unwrap()).abs() < 1e-9);
+        }
+        assert!((mirrored.currents["Q1.c"] + collector_i).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_saturated_switch() {
+        // 0.43 mA of base drive is far more than 5 mA/BF, so the collector current is set by RC
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(VoltageSource::new(5.0)), "vcc", "0", String::from("VCC"));
+        circ.add_bipole_between(Box::new(Resistor::new(10e3)), "vcc", "base", String::from("RB"));
+        circ.add_bipole_between(Box::new(Resistor::new(1e3)), "vcc", "collector", String::from("RC"));
+        circ.add_device_between(Box::new(Bjt::new(Polarity::Npn, BjtParameters::default())),
+            &["collector", "base", "0"], String::from("Q1"));
+
+        let op = circ.operating_point().unwrap();
+        let voltage_ce = op.voltage("collector").unwrap();
+        assert!(voltage_ce > 0.0 && voltage_ce < 0.2);
+        assert!(op.currents["Q1.c"] < 20.0 * op.currents["Q1.b"]);
+    }
+
+    #[test]
+    fn test_early_effect() {
+        let collector_current = |early_voltage: f64, voltage_ce: f64| {
+            let mut circ = Circuit::new(0);
+            circ.add_bipole_between(Box::new(VoltageSource::new(0.65)), "base", "0", String::from("VB"));
+            circ.add_bipole_between(Box::new(VoltageSource::new(voltage_ce)), "collector", "0", String::from("VC"));
+            let parameters = BjtParameters { early_voltage, ..BjtParameters::default() };
+            circ.add_device_between(Box::new(Bjt::new(Polarity::Npn, parameters)), &["collector", "base", "0"],
+                String::from("Q1"));
+            circ.operating_point().unwrap().currents["Q1.c"]
+        };
+
+        assert!((collector_current(f64::INFINITY, 5.0)/collector_current(f64::INFINITY, 1.0) - 1.0).abs() < 1e-6);
+        let expected = (1.0 + (5.0 - 0.65)/50.0)/(1.0 + (1.0 - 0.65)/50.0);
+        assert!((collector_current(50.0, 5.0)/collector_current(50.0, 1.0) - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_junction_capacitances() {
+        let parameters = BjtParameters { capacitance_je: 2e-12, capacitance_jc: 1e-12, ..BjtParameters::default() };
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(VoltageSource::new_ac(0.3, 1.0, 0.0)), "base", "0", String::from("VB"));
+        circ.add_bipole_between(Box::new(VoltageSource::new(5.0)), "collector", "0", String::from("VC"));
+        circ.add_device_between(Box::new(Bjt::new(Polarity::Npn, parameters)), &["collector", "base", "0"],
+            String::from("Q1"));
+
+        // the base draws the charging current of both reverse or weakly forward biased junctions
+        let capacitance = 2e-12 * (1.0 - 0.3/JUNCTION_POTENTIAL).powf(-GRADING_COEFFICIENT)
+            + 1e-12 * (1.0 + 4.7/JUNCTION_POTENTIAL).powf(-GRADING_COEFFICIENT);
+        let ac = circ.ac_sweep(Sweep::Decade, 1, 1e6, 1e8).unwrap();
+        for (frequency, current) in ac.frequencies_hz.iter().zip(ac.currents["Q1.b"].iter()) {
+            let susceptance = 2.0 * consts::PI * frequency * capacitance;
+            assert!((current.im/susceptance - 1.0).abs() < 1e-6);
+        }
+
+        // driven through a resistor the base charges with the junction capacitances
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(VoltageSource::new(0.3)), "in", "0", String::from("VB"));
+        circ.add_bipole_between(Box::new(Resistor::new(10e3)), "in", "base", String::from("RB"));
+        circ.add_device_between(Box::new(Bjt::new(Polarity::Npn, parameters)), &["base", "base", "0"],
+            String::from("Q1"));
+        let out = circ.simulate(1e-6, 1e-9).unwrap();
+        let base = out.voltage("base").unwrap();
+        assert!(base[5] > 0.0 && base[5] < 0.1);
+        assert!(base.iter().zip(base.iter().skip(1)).all(|(previous, next)| next >= previous));
+        assert!((base[out.time.iter().count() - 1] - 0.3).abs() < 1e-3);
+    }
+
+    /// Drain current of a transistor biased by ideal sources; a PMOS sees the mirrored voltages.
+    fn drain_current(channel: Channel, parameters: MosfetParameters, voltage_gs: f64, voltage_ds: f64) -> f64 {
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(VoltageSource::new(channel.sign() * voltage_gs)), "gate", "0", String::from("VG"));
+        circ.add_bipole_between(Box::new(VoltageSource::new(channel.sign() * voltage_ds)), "drain", "0", String::from("VD"));
+        circ.add_device_between(Box::new(Mosfet::new(channel, parameters)), &["drain", "gate", "0"], String::from("M1"));
+        let op = circ.operating_point().unwrap();
+        assert!((op.currents["M1.d"] + op.currents["M1.s"]).abs() < 1e-15 && op.currents["M1.g"] == 0.0);
+        channel.sign() * op.currents["M1.d"]
+    }
+
+    #[test]
+    fn test_mosfet_regions() {
+        // beta = KP W/L = 1 mA/V^2
+        let parameters = MosfetParameters { threshold_voltage: 1.0, transconductance: 1e-4, channel_length_modulation: 0.02,
+            width: 10e-6, length: 1e-6, ..MosfetParameters::default() };
+        let triode = 1e-3 * (2.0 - 0.25) * 0.5 * 1.01;
+        let saturation = 1e-3/2.0 * 4.0 * 1.1;
+
+        assert!(drain_current(Channel::N, parameters, 0.5, 5.0).abs() < 1e-10);
+        assert!((drain_current(Channel::N, parameters, 3.0, 0.5)/triode - 1.0).abs() < 1e-6);
+        assert!((drain_current(Channel::N, parameters, 3.0, 5.0)/saturation - 1.0).abs() < 1e-6);
+        // with the drain below the source the roles swap: the gate is 3.5 V above the drain
+        let reverse = 1e-3 * (2.5 - 0.25) * 0.5 * 1.01;
+        assert!((drain_current(Channel::N, parameters, 3.0, -0.5)/reverse + 1.0).abs() < 1e-6);
+
+        let parameters = MosfetParameters { threshold_voltage: -1.0, ..parameters };
+        assert!(drain_current(Channel::P, parameters, 0.5, 5.0).abs() < 1e-10);
+        assert!((drain_current(Channel::P, parameters, 3.0, 0.5)/triode - 1.0).abs() < 1e-6);
+        assert!((drain_current(Channel::P, parameters, 3.0, 5.0)/saturation - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_body_effect() {
+        let parameters = MosfetParameters { threshold_voltage: 1.0, transconductance: 1e-4, body_effect: 0.5,
+            width: 10e-6, length: 1e-6, ..MosfetParameters::default() };
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(VoltageSource::new(3.0)), "gate", "0", String::from("VG"));
+        circ.add_bipole_between(Box::new(VoltageSource::new(5.0)), "drain", "0", String::from("VD"));
+        circ.add_bipole_between(Box::new(VoltageSource::new(-2.0)), "bulk", "0", String::from("VB"));
+        circ.add_device_between(Box::new(Mosfet::with_bulk(Channel::N, parameters)), &["drain", "gate", "0", "bulk"],
+            String::from("M1"));
+
+        let op = circ.operating_point().unwrap();
+        let threshold = 1.0 + 0.5 * ((0.6f64 + 2.0).sqrt() - 0.6f64.sqrt());
+        assert!((op.currents["M1.d"]/(1e-3/2.0 * (3.0 - threshold).powi(2)) - 1.0).abs() < 1e-6);
+        assert_eq!(op.currents["M1.b"], 0.0);
+
+        // with drain and source swapped the threshold follows the bulk voltage to the drain
+        circ.add_device_between(Box::new(Mosfet::with_bulk(Channel::N, parameters)), &["0", "gate", "drain", "bulk"],
+            String::from("M2"));
+        let op = circ.operating_point().unwrap();
+        assert!((op.currents["M2.s"]/(1e-3/2.0 * (3.0 - threshold).powi(2)) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_cmos_inverter() {
+        let nmos = MosfetParameters { threshold_voltage: 0.7, transconductance: 1e-4, channel_length_modulation: 0.01,
+            width: 2e-6, length: 1e-6, ..MosfetParameters::default() };
+        let pmos = MosfetParameters { threshold_voltage: -0.7, ..nmos };
+        let inverter = |voltage_in: f64| {
+            let mut circ = Circuit::new(0);
+            circ.add_bipole_between(Box::new(VoltageSource::new(5.0)), "vdd", "0", String::from("VDD"));
+            circ.add_bipole_between(Box::new(VoltageSource::new(voltage_in)), "in", "0", String::from("VIN"));
+            circ.add_device_between(Box::new(Mosfet::new(Channel::P, pmos)), &["out", "in", "vdd"], String::from("MP"));
+            circ.add_device_between(Box::new(Mosfet::new(Channel::N, nmos)), &["out", "in", "0"], String::from("MN"));
+            let op = circ.operating_point().unwrap();
+            (op.voltage("out").unwrap(), op.currents["MN.d"])
+        };
+
+        let curve: Vec<(f64, f64)> = (0..=50).map(|step| inverter(0.1 * step as f64)).collect();
+        // the rails are reached with no static current, and the matched pair switches half way
+        assert!((curve[0].0 - 5.0).abs() < 1e-6 && curve[0].1.abs() < 1e-9);
+        assert!(curve[50].0.abs() < 1e-6 && curve[50].1.abs() < 1e-9);
+        assert!((curve[25].0 - 2.5).abs() < 1e-6);
+        assert!(curve.iter().zip(curve.iter().skip(1)).all(|(previous, next)| next.0 <= previous.0 + 1e-9));
+        // past the threshold the output falls steeply
+        assert!(curve[24].0 - curve[26].0 > 2.0);
+    }
+
+    #[test]
+    fn test_gate_capacitance() {
+        let parameters = MosfetParameters { threshold_voltage: 0.7, overlap_capacitance_gs: 1e-9,
+            overlap_capacitance_gd: 0.5e-9, width: 1e-3, ..MosfetParameters::default() };
+        let capacitance = 1.5e-12;
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(VoltageSource::new_ac(0.5, 1.0, 0.0)), "gate", "0", String::from("VG"));
+        circ.add_device_between(Box::new(Mosfet::new(Channel::N, parameters)), &["0", "gate", "0"], String::from("M1"));
+        let ac = circ.ac_sweep(Sweep::Decade, 1, 1e6, 1e8).unwrap();
+        for (frequency, current) in ac.frequencies_hz.iter().zip(ac.currents["M1.g"].iter()) {
+            assert!((current.im/(2.0 * consts::PI * frequency * capacitance) - 1.0).abs() < 1e-9);
+        }
+
+        // driven through a resistor the gate charges with a time constant of 1.5 ns
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(VoltageSource::new(1.0)), "in", "0", String::from("VG"));
+        circ.add_bipole_between(Box::new(Resistor::new(1e3)), "in", "gate", String::from("RG"));
+        circ.add_device_between(Box::new(Mosfet::new(Channel::N, parameters)), &["0", "gate", "0"], String::from("M1"));
+        let out = circ.simulate(1.5e-9, 1e-12).unwrap();
+        let gate = out.voltage("gate").unwrap();
+        assert!((gate[out.time.iter().count() - 1] - (1.0 - (-1.0f64).exp())).abs() < 1e-2);
+    }
+}
@@ -0,0 +1,271 @@
+//! Lossless transmission line (SPICE `T`) with port 1 between `p1`-`n1` and port 2 between
+//! `p2`-`n2`, modelled by the method of characteristics: each port is the characteristic
+//! impedance in series with the wave arriving from the other port one delay earlier.
+
+use std::collections::VecDeque;
+use mathru::algebra::abstr::Complex;
+
+use crate::bipoles::{check_positive, IntegrationMethod};
+use crate::devices::{Device, Solution, Stamper, Step};
+use crate::topology::BranchKind;
+
+/// Line of characteristic impedance `impedance` and one-way delay `delay_sec`, at rest
+/// before the first transient step. Steps are kept within the delay, so the wave arriving
+/// at a port is always interpolated from accepted steps.
+#[derive(Clone)]
+pub struct TransmissionLine {
+    impedance: f64,
+    delay_sec: f64,
+    /// Time of the last accepted step since the start of the history.
+    elapsed_sec: f64,
+    /// `v + impedance * i` leaving port 1 and port 2 at accepted steps, the oldest one at or
+    /// before the delay.
+    waves: VecDeque<(f64, [f64; 2])>
+}
+
+impl TransmissionLine {
+    pub fn new(impedance: f64, delay_sec: f64) -> TransmissionLine {
+        TransmissionLine { impedance, delay_sec, elapsed_sec: 0.0, waves: VecDeque::from([(0.0, [0.0; 2])]) }
+    }
+
+    /// Waves leaving both ports at `time` since the start of the history, linearly
+    /// interpolated between accepted steps.
+    fn waves_at(&self, time: f64) -> [f64; 2] {
+        let next = self.waves.partition_point(|(wave_time, _)| *wave_time <= time);
+        if next == 0 || next == self.waves.len() {
+            return self.waves[next.min(self.waves.len() - 1)].1;
+        }
+        let ((start, before), (end, after)) = (self.waves[next - 1], self.waves[next]);
+        let fraction = (time - start)/(end - start);
+        [0, 1].map(|port| before[port] + fraction * (after[port] - before[port]))
+    }
+
+    /// Voltages behind the characteristic impedance at each port for a step of `timestep_sec`.
+    fn incident(&self, timestep_sec: f64) -> [f64; 2] {
+        let [leaving_1, leaving_2] = self.waves_at(self.elapsed_sec + timestep_sec - self.delay_sec);
+        [leaving_2, leaving_1]
+    }
+
+    /// Current into `p1` and into `p2`.
+    fn port_currents(&self, step: Step, solution: &Solution<f64>) -> [f64; 2] {
+        match step {
+            Step::Transient { timestep_sec, .. } => {
+                let incident = self.incident(timestep_sec);
+                [0, 1].map(|port| (port_voltage(solution, port) - incident[port])/self.impedance)
+            }
+            Step::OperatingPoint => [solution.branch_current(0), -solution.branch_current(0)]
+        }
+    }
+}
+
+fn port_voltage(solution: &Solution<f64>, port: usize) -> f64 {
+    solution.voltage(2 * port) - solution.voltage(2 * port + 1)
+}
+
+impl Device for TransmissionLine {
+    fn terminals(&self) -> &'static [&'static str] {
+        &["p1", "n1", "p2", "n2"]
+    }
+
+    fn branches(&self, step: Step) -> usize {
+        match step {
+            Step::Transient { .. } => 0,
+            Step::OperatingPoint => 1
+        }
+    }
+
+    fn ac_branches(&self) -> usize {2}
+
+    fn stamp(&self, step: Step, stamper: &mut Stamper<f64>) {
+        match step {
+            Step::Transient { timestep_sec, .. } => {
+                let incident = self.incident(timestep_sec);
+                for (port, incident) in incident.into_iter().enumerate() {
+                    let (positive, negative) = (stamper.node(2 * port), stamper.node(2 * port + 1));
+                    stamper.add_conductance(positive, negative, 1.0/self.impedance);
+                    stamper.add_current(positive, negative, -incident/self.impedance);
+                }
+            }
+            // at DC the line is a pair of wires: both ports see the same voltage, and the
+            // current entering one leaves the other
+            Step::OperatingPoint => {
+                let branch = stamper.branch(0);
+                for (terminal, coefficient) in [1.0, -1.0, -1.0, 1.0].into_iter().enumerate() {
+                    stamper.add(stamper.node(terminal), branch, coefficient);
+                    stamper.add(branch, stamper.node(terminal), coefficient);
+                }
+            }
+        }
+    }
+
+    fn stamp_ac(&self, omega: f64, stamper: &mut Stamper<Complex<f64>>) {
+        // v1 - Z0 i1 = lag (v2 + Z0 i2) and v2 - Z0 i2 = lag (v1 + Z0 i1), with lag = exp(-j omega delay)
+        let one = Complex::new(1.0, 0.0);
+        let impedance = Complex::new(self.impedance, 0.0);
+        let lag = Complex::new((omega * self.delay_sec).cos(), -(omega * self.delay_sec).sin());
+        let branches = [stamper.branch(0), stamper.branch(1)];
+        for port in 0..2 {
+            let (positive, negative, branch) = (stamper.node(2 * port), stamper.node(2 * port + 1), branches[port]);
+            let (far_positive, far_negative, far_branch) =
+                (stamper.node(2 - 2 * port), stamper.node(3 - 2 * port), branches[1 - port]);
+            stamper.add(positive, branch, one);
+            stamper.add(negative, branch, -one);
+
+            stamper.add(branch, positive, one);
+            stamper.add(branch, negative, -one);
+            stamper.add(branch, branch, -impedance);
+            stamper.add(branch, far_positive, -lag);
+            stamper.add(branch, far_negative, lag);
+            stamper.add(branch, far_branch, -(lag * impedance));
+        }
+    }
+
+    fn currents(&self, step: Step, solution: &Solution<f64>) -> Vec<f64> {
+        let [port_1, port_2] = self.port_currents(step, solution);
+        vec![port_1, -port_1, port_2, -port_2]
+    }
+
+    fn ac_currents(&self, _omega: f64, solution: &Solution<Complex<f64>>) -> Vec<Complex<f64>> {
+        let zero = Complex::new(0.0, 0.0);
+        let (port_1, port_2) = (solution.branch_current(0), solution.branch_current(1));
+        vec![port_1, zero - port_1, port_2, zero - port_2]
+    }
+
+    fn topology(&self, _step: Step) -> Vec<(usize, usize, BranchKind)> {
+        vec![(0, 1, BranchKind::Conductive), (2, 3, BranchKind::Conductive)]
+    }
+
+    // the waves travelling along the line are memory
+    fn is_dynamic(&self) -> bool {
+        true
+    }
+
+    fn update_state(&mut self, solution: &Solution<f64>, timestep_sec: f64, _method: IntegrationMethod) {
+        // v + Z0 i = 2 v - incident
+        let incident = self.incident(timestep_sec);
+        let waves = [0, 1].map(|port| 2.0 * port_voltage(solution, port) - incident[port]);
+        self.elapsed_sec += timestep_sec;
+        self.waves.push_back((self.elapsed_sec, waves));
+
+        // later steps end after this one, so they look back no further than the delay from it
+        let oldest = self.elapsed_sec - self.delay_sec;
+        while self.waves.len() > 1 && self.waves[1].0 <= oldest {
+            self.waves.pop_front();
+        }
+    }
+
+    // not a corner, but it keeps every step within the delay
+    fn next_breakpoint(&self, time: f64) -> Option<f64> {
+        Some(time + self.delay_sec)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        check_positive("characteristic impedance", self.impedance)?;
+        check_positive("delay", self.delay_sec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mathru::algebra::linear::Vector;
+
+    use super::*;
+    use crate::bipoles::{Circuit, Resistor, SimulationOptions, Sweep, TimestepControl, VoltageSource};
+    use crate::waveforms::{Waveform, WaveformVoltageSource};
+
+    /// A 1 V edge rising over 0.1 us, through 50 ohm into a 50 ohm line delaying it by 1 us.
+    fn driven_line() -> Circuit {
+        let edge = Waveform::Pulse { initial: 0.0, pulsed: 1.0, delay_sec: 0.0, rise_sec: 0.1e-6, fall_sec: 0.1e-6,
+            width_sec: f64::INFINITY, period_sec: f64::INFINITY };
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(WaveformVoltageSource::new_ac(edge, 1.0, 0.0)), "in", "0", String::from("V1"));
+        circ.add_bipole_between(Box::new(Resistor::new(50.0)), "in", "a", String::from("RS"));
+        circ.add_device_between(Box::new(TransmissionLine::new(50.0, 1e-6)), &["a", "0", "b", "0"], String::from("T1"));
+        circ
+    }
+
+    /// Checks that `values` holds each level from its first sample until the next level starts.
+    fn assert_levels(values: &Vector<f64>, levels: &[(usize, f64)]) {
+        for (level, &(start, value)) in levels.iter().enumerate() {
+            let end = levels.get(level + 1).map_or(usize::MAX, |(end, _)| *end);
+            for (sample, actual) in values.iter().enumerate().take(end).skip(start) {
+                assert!((actual - value).abs() < 1e-9, "{sample}: {actual} instead of {value}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_matched_line() {
+        // half the edge enters the line, reaches the far end 1 us later and is absorbed there
+        let mut circ = driven_line();
+        circ.add_bipole_between(Box::new(Resistor::new(50.0)), "b", "0", String::from("RL"));
+        let out = circ.simulate(4e-6, 0.1e-6).unwrap();
+        assert_levels(out.voltage("a").unwrap(), &[(0, 0.0), (1, 0.5)]);
+        assert_levels(out.voltage("b").unwrap(), &[(0, 0.0), (11, 0.5)]);
+        assert_levels(&out.currents["T1.p1"], &[(0, 0.0), (1, 0.01)]);
+        assert_levels(&out.currents["T1.p2"], &[(0, 0.0), (11, -0.01)]);
+
+        // at DC the line is a pair of wires, and in AC a matched line only delays
+        let op = driven_line().operating_point().unwrap();
+        assert_eq!(op.voltage("b"), op.voltage("a"));
+        let ac = circ.ac_sweep(Sweep::Linear, 5, 0.1e6, 0.5e6).unwrap();
+        for (point, frequency_hz) in ac.frequencies_hz.iter().enumerate() {
+            let gain = ac.voltage("b").unwrap()[point]/ac.voltage("a").unwrap()[point];
+            let phase = -2.0 * std::f64::consts::PI * frequency_hz * 1e-6;
+            assert!((gain.re - phase.cos()).abs() < 1e-9 && (gain.im - phase.sin()).abs() < 1e-9, "{frequency_hz}");
+        }
+    }
+
+    #[test]
+    fn test_open_line() {
+        // the edge doubles at the open end and its reflection raises the near end 1 us later
+        let mut circ = driven_line();
+        let out = circ.simulate(4e-6, 0.1e-6).unwrap();
+        assert_levels(out.voltage("a").unwrap(), &[(0, 0.0), (1, 0.5), (21, 1.0)]);
+        assert_levels(out.voltage("b").unwrap(), &[(0, 0.0), (11, 1.0)]);
+        assert_levels(&out.currents["T1.p1"], &[(0, 0.0), (1, 0.01), (21, 0.0)]);
+        assert_levels(&out.currents["T1.p2"], &[(0, 0.0)]);
+
+        // adaptive steps never exceed the delay, and the delayed corners of the edge land on steps
+        let options = SimulationOptions { timestep_control: Some(TimestepControl::new(1e-12, 1e-3)), ..Default::default() };
+        let out = driven_line().simulate_with_options(4e-6, 1e-9, &options).unwrap();
+        let time = out.time.iter().copied().collect::<Vec<f64>>();
+        assert!(time.windows(2).all(|pair| pair[1] - pair[0] <= 1e-6 * (1.0 + 1e-6)));
+        for (time, voltage) in time.iter().zip(out.voltage("b").unwrap().iter()) {
+            let expected = ((time - 1e-6)/0.1e-6).clamp(0.0, 1.0);
+            assert!((voltage - expected).abs() < 1e-6, "{time}");
+        }
+
+        // a quarter wave line open at the far end shorts the near end
+        let ac = circ.ac_sweep(Sweep::Linear, 1, 0.25e6, 0.25e6).unwrap();
+        let voltage = ac.voltage("a").unwrap()[0];
+        assert!(voltage.re.hypot(voltage.im) < 1e-9);
+    }
+
+    #[test]
+    fn test_shorted_line() {
+        // the short reflects the edge inverted, cancelling it at the near end 1 us later
+        let mut circ = driven_line();
+        circ.add_bipole_between(Box::new(VoltageSource::new(0.0)), "b", "0", String::from("VS"));
+        let out = circ.simulate(4e-6, 0.1e-6).unwrap();
+        assert_levels(out.voltage("a").unwrap(), &[(0, 0.0), (1, 0.5), (21, 0.0)]);
+        assert_levels(out.voltage("b").unwrap(), &[(0, 0.0)]);
+        assert_levels(&out.currents["T1.p1"], &[(0, 0.0), (1, 0.01), (21, 0.02)]);
+        assert_levels(&out.currents["T1.p2"], &[(0, 0.0), (11, -0.02)]);
+    }
+
+    #[test]
+    fn test_interpolated_delay() {
+        // a delay of 33.3 steps: the sine at the far end comes from the samples around 33.3 steps back
+        let sine = Waveform::Sin { offset: 0.0, amplitude: 1.0, frequency_hz: 1e6, delay_sec: 0.0, damping: 0.0, phase_deg: 0.0 };
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(WaveformVoltageSource::new(sine)), "a", "0", String::from("V1"));
+        circ.add_device_between(Box::new(TransmissionLine::new(75.0, 0.333e-6)), &["a", "0", "b", "0"], String::from("T1"));
+        circ.add_bipole_between(Box::new(Resistor::new(75.0)), "b", "0", String::from("RL"));
+        let out = circ.simulate(2e-6, 0.01e-6).unwrap();
+        for (time, voltage) in out.time.iter().zip(out.voltage("b").unwrap().iter()) {
+            let expected = if *time > 0.333e-6 { (2.0 * std::f64::consts::PI * 1e6 * (time - 0.333e-6)).sin() } else { 0.0 };
+            assert!((voltage - expected).abs() < 1e-3, "{time}");
+        }
+    }
+}
@@ -0,0 +1,413 @@
+//! Time-varying waveforms of independent sources, with the parameters of their SPICE
+//! counterparts, and the voltage and current sources that follow them.
+
+use std::f64::consts;
+use mathru::algebra::abstr::Complex;
+
+use crate::bipoles::{check_finite, check_positive, phasor, AcModel, BipoleBehaviour, IntegrationMethod, Model};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Waveform {
+    /// `offset + amplitude * exp(-damping * (t - delay)) * sin(2 pi frequency (t - delay) + phase)`
+    /// after the delay, and its value at the delay before.
+    Sin { offset: f64, amplitude: f64, frequency_hz: f64, delay_sec: f64, damping: f64, phase_deg: f64 },
+    /// Trapezoidal pulses from `initial` to `pulsed`, repeating every period; an infinite
+    /// width or period gives a single edge or a single pulse.
+    Pulse { initial: f64, pulsed: f64, delay_sec: f64, rise_sec: f64, fall_sec: f64, width_sec: f64, period_sec: f64 },
+    /// Straight lines between `(time, value)` points with increasing times, constant before the
+    /// first point and after the last one.
+    Pwl(Vec<(f64, f64)>),
+    /// Exponential rise from `initial` towards `pulsed` after the rise delay, and back towards
+    /// `initial` after the fall delay.
+    Exp { initial: f64, pulsed: f64, rise_delay_sec: f64, rise_tau_sec: f64, fall_delay_sec: f64, fall_tau_sec: f64 },
+    /// Single-frequency FM: `offset + amplitude * sin(2 pi carrier t + modulation_index * sin(2 pi signal t))`.
+    Sffm { offset: f64, amplitude: f64, carrier_hz: f64, modulation_index: f64, signal_hz: f64 }
+}
+
+impl Waveform {
+    /// Points of a `Pwl` waveform from CSV text with one `time,value` pair per line. Blank
+    /// lines and lines starting with `#` are skipped, and the first line may be a header.
+    pub fn pwl_from_csv(text: &str) -> Result<Waveform, String> {
+        let mut points = Vec::new();
+        let mut header_allowed = true;
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+            let point = match fields[..] {
+                [time, value] => time.parse::<f64>().ok().zip(value.parse::<f64>().ok()),
+                _ => None
+            };
+            match point {
+                Some(point) => points.push(point),
+                None if header_allowed => {}
+                None => return Err(format!("line {}: expected 'time,value', got '{line}'", line_number + 1))
+            }
+            header_allowed = false;
+        }
+        if points.is_empty() {
+            return Err(String::from("no points in PWL data"));
+        }
+        Ok(Waveform::Pwl(points))
+    }
+
+    pub fn value(&self, time: f64) -> f64 {
+        match *self {
+            Waveform::Sin { offset, amplitude, frequency_hz, delay_sec, damping, phase_deg } => {
+                let phase = phase_deg.to_radians();
+                if time <= delay_sec {
+                    return offset + amplitude * phase.sin();
+                }
+                let elapsed = time - delay_sec;
+                offset + amplitude * (-damping * elapsed).exp() * (2.0 * consts::PI * frequency_hz * elapsed + phase).sin()
+            }
+            Waveform::Pulse { initial, pulsed, delay_sec, rise_sec, fall_sec, width_sec, period_sec } => {
+                if time < delay_sec {
+                    return initial;
+                }
+                let elapsed = if period_sec.is_finite() { (time - delay_sec) % period_sec } else { time - delay_sec };
+                if elapsed < rise_sec {
+                    initial + (pulsed - initial) * elapsed/rise_sec
+                } else if elapsed < rise_sec + width_sec {
+                    pulsed
+                } else if elapsed < rise_sec + width_sec + fall_sec {
+                    pulsed + (initial - pulsed) * (elapsed - rise_sec - width_sec)/fall_sec
+                } else {
+                    initial
+                }
+            }
+            Waveform::Pwl(ref points) => {
+                let after = points.partition_point(|(point_time, _)| *point_time <= time);
+                match (after.checked_sub(1).map(|before| points[before]), points.get(after)) {
+                    (None, _) => points[0].1,
+                    (Some((_, value)), None) => value,
+                    (Some((time0, value0)), Some((time1, value1))) => value0 + (value1 - value0) * (time - time0)/(time1 - time0)
+                }
+            }
+            Waveform::Exp { initial, pulsed, rise_delay_sec, rise_tau_sec, fall_delay_sec, fall_tau_sec } => {
+                let mut value = initial;
+                if time > rise_delay_sec {
+                    value += (pulsed - initial) * (1.0 - (-(time - rise_delay_sec)/rise_tau_sec).exp());
+                }
+                if time > fall_delay_sec {
+                    value += (initial - pulsed) * (1.0 - (-(time - fall_delay_sec)/fall_tau_sec).exp());
+                }
+                value
+            }
+            Waveform::Sffm { offset, amplitude, carrier_hz, modulation_index, signal_hz } => {
+                offset + amplitude * (2.0 * consts::PI * carrier_hz * time
+                    + modulation_index * (2.0 * consts::PI * signal_hz * time).sin()).sin()
+            }
+        }
+    }
+
+    /// First corner of the waveform strictly after `time`.
+    pub fn next_breakpoint(&self, time: f64) -> Option<f64> {
+        let first_after = |corners: &mut dyn Iterator<Item = f64>|
+            corners.filter(|corner| *corner > time && corner.is_finite()).reduce(f64::min);
+        match *self {
+            Waveform::Sin { delay_sec, .. } => Some(delay_sec).filter(|delay_sec| *delay_sec > time),
+            Waveform::Pulse { delay_sec, rise_sec, fall_sec, width_sec, period_sec, .. } => {
+                let offsets = [0.0, rise_sec, rise_sec + width_sec, rise_sec + width_sec + fall_sec];
+                // corners of the cycle under way and of the next one
+                let starts = if period_sec.is_finite() {
+                    let cycle = ((time - delay_sec)/period_sec).floor().max(0.0);
+                    vec![delay_sec + cycle * period_sec, delay_sec + (cycle + 1.0) * period_sec]
+                } else {
+                    vec![delay_sec]
+                };
+                first_after(&mut starts.into_iter().flat_map(|start| offsets.map(|offset| start + offset)))
+            }
+            Waveform::Pwl(ref points) => first_after(&mut points.iter().map(|(point_time, _)| *point_time)),
+            Waveform::Exp { rise_delay_sec, fall_delay_sec, .. } => first_after(&mut [rise_delay_sec, fall_delay_sec].into_iter()),
+            Waveform::Sffm { .. } => None
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Waveform::Sin { offset, amplitude, frequency_hz, delay_sec, damping, phase_deg } => {
+                for (parameter, value) in [("offset", offset), ("amplitude", amplitude), ("frequency", frequency_hz),
+                    ("delay", delay_sec), ("damping", damping), ("phase", phase_deg)] {
+                    check_finite(parameter, value)?;
+                }
+                Ok(())
+            }
+            Waveform::Pulse { initial, pulsed, delay_sec, rise_sec, fall_sec, width_sec, period_sec } => {
+                for (parameter, value) in [("initial value", initial), ("pulsed value", pulsed), ("delay", delay_sec),
+                    ("rise time", rise_sec), ("fall time", fall_sec)] {
+                    check_finite(parameter, value)?;
+                }
+                for (parameter, value) in [("rise time", rise_sec), ("fall time", fall_sec), ("pulse width", width_sec)] {
+                    if value.is_nan() || value < 0.0 {
+                        return Err(format!("{parameter} must not be negative, got {value}"));
+                    }
+                }
+                if period_sec.is_nan() || period_sec < rise_sec + width_sec + fall_sec {
+                    return Err(format!("period must cover rise, width and fall, got {period_sec}"));
+                }
+                Ok(())
+            }
+            Waveform::Pwl(ref points) => {
+                for (time, value) in points {
+                    check_finite("PWL time", *time)?;
+                    check_finite("PWL value", *value)?;
+                }
+                match points.windows(2).find(|pair| pair[1].0 <= pair[0].0) {
+                    Some(pair) => Err(format!("PWL times must increase, got {} after {}", pair[1].0, pair[0].0)),
+                    None if points.is_empty() => Err(String::from("PWL needs at least one point")),
+                    None => Ok(())
+                }
+            }
+            Waveform::Exp { initial, pulsed, rise_delay_sec, rise_tau_sec, fall_delay_sec, fall_tau_sec } => {
+                check_finite("initial value", initial)?;
+                check_finite("pulsed value", pulsed)?;
+                check_finite("rise delay", rise_delay_sec)?;
+                check_positive("rise time constant", rise_tau_sec)?;
+                check_positive("fall time constant", fall_tau_sec)?;
+                if fall_delay_sec.is_nan() || fall_delay_sec < rise_delay_sec {
+                    return Err(format!("fall delay must not come before the rise delay, got {fall_delay_sec}"));
+                }
+                Ok(())
+            }
+            Waveform::Sffm { offset, amplitude, carrier_hz, modulation_index, signal_hz } => {
+                for (parameter, value) in [("offset", offset), ("amplitude", amplitude), ("carrier frequency", carrier_hz),
+                    ("modulation index", modulation_index), ("signal frequency", signal_hz)] {
+                    check_finite(parameter, value)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Voltage source following a waveform; the operating point uses its DC value if it has
+/// one, its value at time zero otherwise.
+#[derive(Clone)]
+pub struct WaveformVoltageSource {
+    waveform: Waveform,
+    dc_value: Option<f64>,
+    ac_magnitude: f64,
+    ac_phase_deg: f64
+}
+
+impl WaveformVoltageSource {
+    pub fn new(waveform: Waveform) -> WaveformVoltageSource {
+        WaveformVoltageSource { waveform, dc_value: None, ac_magnitude: 0.0, ac_phase_deg: 0.0 }
+    }
+
+    pub fn new_ac(waveform: Waveform, ac_magnitude: f64, ac_phase_deg: f64) -> WaveformVoltageSource {
+        WaveformVoltageSource { waveform, dc_value: None, ac_magnitude, ac_phase_deg }
+    }
+
+    /// Source biased at `dc_value` for the operating point and AC analysis, as a SPICE card
+    /// with both a DC value and a waveform.
+    pub fn new_dc(waveform: Waveform, dc_value: f64, ac_magnitude: f64, ac_phase_deg: f64) -> WaveformVoltageSource {
+        WaveformVoltageSource { waveform, dc_value: Some(dc_value), ac_magnitude, ac_phase_deg }
+    }
+}
+
+impl BipoleBehaviour for WaveformVoltageSource {
+    fn linear_companion(&self, _timestep_sec: f64, current_time_sec: f64, _method: IntegrationMethod) -> Model {
+        Model::VoltageSource(self.waveform.value(current_time_sec))
+    }
+
+    fn dc_companion(&self) -> Model {
+        Model::VoltageSource(self.dc_value.unwrap_or_else(|| self.waveform.value(0.0)))
+    }
+
+    fn ac_companion(&self, _omega: f64) -> AcModel {
+        AcModel::VoltageSource(phasor(self.ac_magnitude, self.ac_phase_deg))
+    }
+
+    fn next_breakpoint(&self, time: f64) -> Option<f64> {
+        self.waveform.next_breakpoint(time)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.waveform.validate()?;
+        if let Some(dc_value) = self.dc_value {
+            check_finite("DC value", dc_value)?;
+        }
+        check_finite("AC magnitude", self.ac_magnitude)?;
+        check_finite("AC phase", self.ac_phase_deg)
+    }
+}
+
+/// Current source following a waveform, flowing through the source from anode to catode;
+/// the operating point uses its DC value if it has one, its value at time zero otherwise.
+#[derive(Clone)]
+pub struct WaveformCurrentSource {
+    waveform: Waveform,
+    dc_value: Option<f64>,
+    ac_magnitude: f64,
+    ac_phase_deg: f64
+}
+
+impl WaveformCurrentSource {
+    pub fn new(waveform: Waveform) -> WaveformCurrentSource {
+        WaveformCurrentSource { waveform, dc_value: None, ac_magnitude: 0.0, ac_phase_deg: 0.0 }
+    }
+
+    pub fn new_ac(waveform: Waveform, ac_magnitude: f64, ac_phase_deg: f64) -> WaveformCurrentSource {
+        WaveformCurrentSource { waveform, dc_value: None, ac_magnitude, ac_phase_deg }
+    }
+
+    /// Source biased at `dc_value` for the operating point and AC analysis, as a SPICE card
+    /// with both a DC value and a waveform.
+    pub fn new_dc(waveform: Waveform, dc_value: f64, ac_magnitude: f64, ac_phase_deg: f64) -> WaveformCurrentSource {
+        WaveformCurrentSource { waveform, dc_value: Some(dc_value), ac_magnitude, ac_phase_deg }
+    }
+}
+
+impl BipoleBehaviour for WaveformCurrentSource {
+    fn linear_companion(&self, _timestep_sec: f64, current_time_sec: f64, _method: IntegrationMethod) -> Model {
+        Model::ConduttanceCurrentSource { conduttance: 0.0, current: self.waveform.value(current_time_sec) }
+    }
+
+    fn dc_companion(&self) -> Model {
+        Model::ConduttanceCurrentSource { conduttance: 0.0, current: self.dc_value.unwrap_or_else(|| self.waveform.value(0.0)) }
+    }
+
+    fn ac_companion(&self, _omega: f64) -> AcModel {
+        AcModel::AdmittanceCurrentSource { admittance: Complex::new(0.0, 0.0), current: phasor(self.ac_magnitude, self.ac_phase_deg) }
+    }
+
+    fn next_breakpoint(&self, time: f64) -> Option<f64> {
+        self.waveform.next_breakpoint(time)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.waveform.validate()?;
+        if let Some(dc_value) = self.dc_value {
+            check_finite("DC value", dc_value)?;
+        }
+        check_finite("AC magnitude", self.ac_magnitude)?;
+        check_finite("AC phase", self.ac_phase_deg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bipoles::{Capacitor, Circuit, Resistor, SimulationOptions, TimestepControl};
+
+    #[test]
+    fn test_waveform_values() {
+        let sin = Waveform::Sin { offset: 1.0, amplitude: 2.0, frequency_hz: 1e3, delay_sec: 1e-3,
+            damping: 100.0, phase_deg: 90.0 };
+        assert!((sin.value(0.0) - 3.0).abs() < 1e-12);
+        assert!((sin.value(1.5e-3) - (1.0 - 2.0 * (-0.05f64).exp())).abs() < 1e-12);
+
+        let pulse = Waveform::Pulse { initial: -1.0, pulsed: 1.0, delay_sec: 1.0, rise_sec: 0.5, fall_sec: 1.0,
+            width_sec: 2.0, period_sec: 5.0 };
+        for (time, value) in [(0.5, -1.0), (1.25, 0.0), (2.0, 1.0), (4.0, 0.0), (5.5, -1.0), (6.25, 0.0), (8.0, 1.0)] {
+            assert!((pulse.value(time) - value).abs() < 1e-12, "{time}");
+        }
+        let step = Waveform::Pulse { initial: 0.0, pulsed: 5.0, delay_sec: 1.0, rise_sec: 0.0, fall_sec: 0.0,
+            width_sec: f64::INFINITY, period_sec: f64::INFINITY };
+        assert_eq!((step.value(0.5), step.value(1.0), step.value(1e9)), (0.0, 5.0, 5.0));
+
+        let pwl = Waveform::Pwl(vec![(1.0, 2.0), (2.0, 4.0), (4.0, 0.0)]);
+        for (time, value) in [(0.0, 2.0), (1.5, 3.0), (2.0, 4.0), (3.0, 2.0), (5.0, 0.0)] {
+            assert!((pwl.value(time) - value).abs() < 1e-12, "{time}");
+        }
+
+        let exp = Waveform::Exp { initial: 0.0, pulsed: 1.0, rise_delay_sec: 1.0, rise_tau_sec: 1.0,
+            fall_delay_sec: 2.0, fall_tau_sec: 0.5 };
+        assert_eq!(exp.value(1.0), 0.0);
+        assert!((exp.value(2.0) - (1.0 - (-1.0f64).exp())).abs() < 1e-12);
+        assert!((exp.value(2.5) - (1.0 - (-1.5f64).exp() - (1.0 - (-1.0f64).exp()))).abs() < 1e-12);
+
+        // without modulation SFFM is a plain sine at the carrier
+        let sffm = Waveform::Sffm { offset: 0.5, amplitude: 2.0, carrier_hz: 50.0, modulation_index: 0.0, signal_hz: 5.0 };
+        assert!((sffm.value(1e-3) - (0.5 + 2.0 * (2.0 * consts::PI * 0.05f64).sin())).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let pulse = Waveform::Pulse { initial: 0.0, pulsed: 1.0, delay_sec: 1.0, rise_sec: 0.5, fall_sec: 1.0,
+            width_sec: 2.0, period_sec: 5.0 };
+        let mut corners = Vec::new();
+        let mut time = 0.0;
+        while let Some(corner) = pulse.next_breakpoint(time).filter(|corner| *corner < 12.0) {
+            corners.push(corner);
+            time = corner;
+        }
+        assert_eq!(corners, [1.0, 1.5, 3.5, 4.5, 6.0, 6.5, 8.5, 9.5, 11.0, 11.5]);
+
+        let step = Waveform::Pulse { initial: 0.0, pulsed: 1.0, delay_sec: 1.0, rise_sec: 0.0, fall_sec: 0.0,
+            width_sec: f64::INFINITY, period_sec: f64::INFINITY };
+        assert_eq!((step.next_breakpoint(0.0), step.next_breakpoint(1.0)), (Some(1.0), None));
+
+        let pwl = Waveform::Pwl(vec![(0.0, 0.0), (1.0, 1.0), (3.0, 0.0)]);
+        assert_eq!((pwl.next_breakpoint(0.0), pwl.next_breakpoint(2.0), pwl.next_breakpoint(3.0)), (Some(1.0), Some(3.0), None));
+
+        let exp = Waveform::Exp { initial: 0.0, pulsed: 1.0, rise_delay_sec: 1.0, rise_tau_sec: 1.0,
+            fall_delay_sec: f64::INFINITY, fall_tau_sec: 1.0 };
+        assert_eq!((exp.next_breakpoint(0.0), exp.next_breakpoint(1.0)), (Some(1.0), None));
+    }
+
+    #[test]
+    fn test_pwl_from_csv() {
+        let waveform = Waveform::pwl_from_csv("time,voltage\n# ramp\n0, 0\n\n1e-3, 5\n2e-3,5\n").unwrap();
+        assert_eq!(waveform, Waveform::Pwl(vec![(0.0, 0.0), (1e-3, 5.0), (2e-3, 5.0)]));
+
+        assert_eq!(Waveform::pwl_from_csv("0,0\n1,x\n"), Err(String::from("line 2: expected 'time,value', got '1,x'")));
+        assert_eq!(Waveform::pwl_from_csv("# nothing\n"), Err(String::from("no points in PWL data")));
+        assert_eq!(Waveform::Pwl(vec![(0.0, 0.0), (0.0, 1.0)]).validate(),
+            Err(String::from("PWL times must increase, got 0 after 0")));
+    }
+
+    /// An RC low-pass driven by a 0.1 ms ramp starting between two samples.
+    fn ramp_rc() -> Circuit {
+        let ramp = Waveform::Pulse { initial: 0.0, pulsed: 1.0, delay_sec: 0.25e-3, rise_sec: 0.1e-3, fall_sec: 0.0,
+            width_sec: f64::INFINITY, period_sec: f64::INFINITY };
+        let mut circ = Circuit::new(0);
+        circ.add_bipole_between(Box::new(WaveformVoltageSource::new(ramp)), "in", "0", String::from("V1"));
+        circ.add_bipole_between(Box::new(Resistor::new(1e3)), "in", "out", String::from("R1"));
+        circ.add_bipole_between(Box::new(Capacitor::new(1e-6, 0.0)), "out", "0", String::from("C1"));
+        circ
+    }
+
+    #[test]
+    fn test_steps_land_on_breakpoints() {
+        // response of the RC to the rising edge, as the difference of two ramps
+        let tau = 1e-3;
+        let ramp = |elapsed: f64| (elapsed - tau * (1.0 - (-elapsed/tau).exp()))/0.1e-3;
+        let ramp_response = |elapsed: f64| ramp(elapsed) - ramp((elapsed - 0.1e-3).max(0.0));
+
+        let trapezoidal = SimulationOptions { method: IntegrationMethod::Trapezoidal, ..Default::default() };
+        let out = ramp_rc().simulate_with_options(1e-3, 1e-4, &trapezoidal).unwrap();
+        let time = &out.time;
+        assert_eq!(time.iter().count(), 10);
+        assert_eq!(time[3], 3.0 * 1e-4);
+        let voltage = out.voltage("out").unwrap();
+        // the samples before the ramp stay at zero, and the sample half way up it follows the
+        // ramp rather than a whole step of it
+        assert_eq!(voltage[2], 0.0);
+        assert!((voltage[3] - ramp_response(0.05e-3)).abs() < 0.02 * ramp_response(0.05e-3));
+        assert!((voltage[5] - ramp_response(0.25e-3)).abs() < 0.02 * ramp_response(0.25e-3));
+
+        let options = SimulationOptions { timestep_control: Some(TimestepControl::new(1e-9, 1e-4)), ..Default::default() };
+        let out = ramp_rc().simulate_with_options(1e-3, 1e-4, &options).unwrap();
+        for corner in [0.25e-3, 0.35e-3] {
+            assert!(out.time.iter().any(|time| (time - corner).abs() < 1e-15), "{corner}");
+        }
+    }
+
+    #[test]
+    fn test_waveform_current_source() {
+        let mut circ = Circuit::new(0);
+        let pwl = Waveform::Pwl(vec![(0.0, 1e-3), (1e-3, 2e-3)]);
+        circ.add_bipole_between(Box::new(WaveformCurrentSource::new(pwl)), "0", "out", String::from("I1"));
+        circ.add_bipole_between(Box::new(Resistor::new(1e3)), "out", "0", String::from("R1"));
+        let out = circ.simulate(2e-3, 0.5e-3).unwrap();
+        let voltage = out.voltage("out").unwrap();
+        for (sample, expected) in [(0, 1.0), (1, 1.5), (2, 2.0), (3, 2.0)] {
+            assert!((voltage[sample] - expected).abs() < 1e-12, "{sample}");
+        }
+    }
+}